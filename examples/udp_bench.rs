@@ -0,0 +1,169 @@
+//! Load generator for the UDP server.
+//!
+//! `cargo run --release --example udp_bench -- qps [addr] [clients] [seconds] [name]`
+//! keeps `clients` sockets asking for `name` as fast as they get answers
+//! and reports answers per second.
+//!
+//! `cargo run --release --example udp_bench -- sources [addr] [count] [name]`
+//! sends one query from each of `count` fresh source ports, the way a flood
+//! with spoofed source addresses looks to the server, and reports how many
+//! were answered.
+//!
+//! `cargo run --release --example udp_bench -- burst [addr] [count] [zone]`
+//! sends `count` queries for different names below `zone` at once from a
+//! single socket, like a browser opening a page, and reports how long it
+//! took until all of them were answered.
+
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{net::UdpSocket, time::timeout};
+
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.extend_from_slice(&[0, 0, 1, 0, 1]);
+    packet
+}
+
+async fn client(server: SocketAddr, name: String, until: Instant, answered: Arc<AtomicU64>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server).await.unwrap();
+    let mut buffer = [0; 4096];
+    let mut id: u16 = 0;
+
+    while Instant::now() < until {
+        id = id.wrapping_add(1);
+        if socket.send(&query(id, &name)).await.is_err() {
+            continue;
+        }
+        // Lost answers are retried after a while instead of stalling.
+        if let Ok(Ok(_)) = timeout(Duration::from_millis(500), socket.recv(&mut buffer)).await {
+            answered.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn qps(server: SocketAddr, clients: usize, seconds: u64, name: String) {
+    let answered = Arc::new(AtomicU64::new(0));
+    let start = Instant::now();
+    let until = start + Duration::from_secs(seconds);
+
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| tokio::spawn(client(server, name.clone(), until, answered.clone())))
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let answered = answered.load(Ordering::Relaxed);
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{} clients, {:.1}s: {} answers, {:.0} qps",
+        clients,
+        elapsed,
+        answered,
+        answered as f64 / elapsed
+    );
+}
+
+async fn sources(server: SocketAddr, count: usize, name: String) {
+    let start = Instant::now();
+    let mut answered = 0;
+
+    // A few at a time, so the generator doesn't run out of ports.
+    for batch in (0..count).collect::<Vec<_>>().chunks(256) {
+        let tasks: Vec<_> = batch
+            .iter()
+            .map(|&i| {
+                let name = name.clone();
+                tokio::spawn(async move {
+                    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                    socket
+                        .send_to(&query(i as u16, &name), server)
+                        .await
+                        .unwrap();
+                    let mut buffer = [0; 4096];
+                    timeout(Duration::from_secs(1), socket.recv(&mut buffer))
+                        .await
+                        .is_ok()
+                })
+            })
+            .collect();
+        for task in tasks {
+            if task.await.unwrap() {
+                answered += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} sources in {:.1}s: {} answered",
+        count,
+        start.elapsed().as_secs_f64(),
+        answered
+    );
+}
+
+async fn burst(server: SocketAddr, count: usize, zone: String) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server).await.unwrap();
+    let start = Instant::now();
+
+    for i in 0..count {
+        let name = format!("name{}.{}", i, zone);
+        socket.send(&query(i as u16, &name)).await.unwrap();
+    }
+
+    let mut buffer = [0; 4096];
+    let mut answered = 0;
+    while answered < count {
+        match timeout(Duration::from_secs(30), socket.recv(&mut buffer)).await {
+            Ok(Ok(_)) => answered += 1,
+            _ => break,
+        }
+    }
+
+    println!(
+        "{} queries from one socket: {} answered in {:.2}s",
+        count,
+        answered,
+        start.elapsed().as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let arg = |i: usize, default: &str| args.get(i).cloned().unwrap_or(default.to_string());
+    let server: SocketAddr = arg(1, "127.0.0.1:5353").parse().unwrap();
+
+    match arg(0, "qps").as_str() {
+        "qps" => {
+            let clients = arg(2, "64").parse().unwrap();
+            let seconds = arg(3, "10").parse().unwrap();
+            qps(server, clients, seconds, arg(4, "bench.redirect.com")).await
+        }
+        "sources" => {
+            let count = arg(2, "50000").parse().unwrap();
+            sources(server, count, arg(3, "bench.redirect.com")).await
+        }
+        "burst" => {
+            let count = arg(2, "20").parse().unwrap();
+            burst(server, count, arg(3, "burst.test")).await
+        }
+        mode => eprintln!("Unknown mode {}, expected qps, sources or burst", mode),
+    }
+}
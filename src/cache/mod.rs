@@ -0,0 +1,520 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+pub mod store;
+
+use crate::{
+    config::CacheSettings,
+    protocol::{
+        dns_packet::DnsPacket, dns_question::DnsQuestion, dns_record::DnsRecord,
+        query_class::QueryClass, query_type::QueryType, result_code::ResultCode,
+    },
+};
+
+/// Answers are cached per question, names compared without case.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: String,
+    pub qtype: QueryType,
+    pub qclass: QueryClass,
+}
+
+impl CacheKey {
+    pub fn new(question: &DnsQuestion) -> CacheKey {
+        CacheKey {
+            name: question.name.trim_end_matches('.').to_lowercase(),
+            qtype: question.qtype,
+            qclass: question.qclass,
+        }
+    }
+}
+
+/// TTL of stale answers, so clients come back soon for a fresh one
+/// (RFC 8767 4).
+const STALE_TTL: u32 = 30;
+
+struct CacheEntry {
+    rescode: ResultCode,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    resources: Vec<DnsRecord>,
+    stored: Instant,
+    /// Seconds the entry stays valid after it was stored.
+    ttl: u32,
+    /// When the entry was last used, its key in `CacheInner::lru`.
+    used: u64,
+    /// Times it was answered from while fresh.
+    hits: u32,
+    /// Whether somebody is already fetching a fresh copy.
+    prefetching: bool,
+}
+
+impl CacheEntry {
+    fn to_packet(&self, ttl: impl Fn(u32) -> u32) -> DnsPacket {
+        let records = |records: &[DnsRecord]| -> Vec<DnsRecord> {
+            records
+                .iter()
+                .cloned()
+                .map(|mut record| {
+                    if let Some(old) = record.get_ttl() {
+                        record.set_ttl(ttl(old));
+                    }
+                    record
+                })
+                .collect()
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = self.rescode;
+        packet.answers = records(&self.answers);
+        packet.authorities = records(&self.authorities);
+        packet.resources = records(&self.resources);
+        packet
+    }
+}
+
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Keys by when they were last used, oldest first.
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl CacheInner {
+    fn touch(&mut self, key: &CacheKey) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.used);
+            entry.used = self.tick;
+            self.lru.insert(self.tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+        }
+    }
+
+    /// Store `entry` as the most recently used one, making room for it.
+    fn add(&mut self, key: CacheKey, entry: CacheEntry, max_entries: usize) {
+        self.remove(&key);
+        self.entries.insert(key.clone(), entry);
+        self.touch(&key);
+
+        while self.entries.len() > max_entries {
+            let oldest = match self.lru.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+/// Upstream answers kept for as long as their TTLs allow, so repeated
+/// questions don't need another round trip. Cheap to clone, clones share
+/// the same entries.
+#[derive(Clone)]
+pub struct Cache {
+    inner: Arc<Mutex<CacheInner>>,
+    max_entries: usize,
+    max_ttl: u32,
+    max_negative_ttl: u32,
+    /// Seconds expired entries are kept around for when upstreams fail.
+    stale_ttl: u32,
+    /// Fresh hits that make an entry worth refreshing before it expires,
+    /// 0 never refreshes.
+    prefetch_hits: u32,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, max_ttl: u32, max_negative_ttl: u32) -> Cache {
+        Cache {
+            inner: Arc::new(Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            })),
+            max_entries,
+            max_ttl,
+            max_negative_ttl,
+            stale_ttl: 0,
+            prefetch_hits: 0,
+        }
+    }
+
+    /// Keep answers for `stale_ttl` seconds after they expire, to answer
+    /// with when no upstream can (RFC 8767).
+    pub fn serve_stale(mut self, stale_ttl: u32) -> Cache {
+        self.stale_ttl = stale_ttl;
+        self
+    }
+
+    /// Refresh answers asked for at least `hits` times shortly before they
+    /// expire.
+    pub fn prefetch(mut self, hits: u32) -> Cache {
+        self.prefetch_hits = hits;
+        self
+    }
+
+    pub fn from_settings(settings: &CacheSettings) -> Cache {
+        let max_entries = match settings.enabled {
+            true => settings.max_entries,
+            false => 0,
+        };
+        let stale_ttl = match settings.serve_stale {
+            true => settings.max_stale_ttl,
+            false => 0,
+        };
+        let prefetch_hits = match settings.prefetch {
+            true => settings.prefetch_hits,
+            false => 0,
+        };
+
+        Cache::new(max_entries, settings.max_ttl, settings.max_negative_ttl)
+            .serve_stale(stale_ttl)
+            .prefetch(prefetch_hits)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    /// The cached answer to `question`, with TTLs lowered by the time it
+    /// has spent in the cache and never past when the entry expires.
+    pub fn get(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        self.get_at(question, Instant::now())
+    }
+
+    fn get_at(&self, question: &DnsQuestion, now: Instant) -> Option<DnsPacket> {
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+
+        let entry = inner.entries.get_mut(&key)?;
+        let age = now.saturating_duration_since(entry.stored).as_secs();
+        if age >= entry.ttl as u64 {
+            // Expired, but maybe still good enough when upstreams fail.
+            if age >= entry.ttl as u64 + self.stale_ttl as u64 {
+                inner.remove(&key);
+            }
+            return None;
+        }
+
+        // Records can't outlive the entry, which may have been capped.
+        let age = age as u32;
+        let left = entry.ttl - age;
+        entry.hits += 1;
+        let packet = entry.to_packet(|ttl| ttl.saturating_sub(age).min(left));
+
+        inner.touch(&key);
+        Some(packet)
+    }
+
+    /// The expired answer to `question`, if it expired recently enough to
+    /// stand in for a fresh one.
+    pub fn get_stale(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        self.get_stale_at(question, Instant::now())
+    }
+
+    fn get_stale_at(&self, question: &DnsQuestion, now: Instant) -> Option<DnsPacket> {
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+
+        let entry = inner.entries.get(&key)?;
+        let age = now.saturating_duration_since(entry.stored).as_secs();
+        if age >= entry.ttl as u64 + self.stale_ttl as u64 {
+            inner.remove(&key);
+            return None;
+        }
+
+        let packet = entry.to_packet(|ttl| ttl.min(STALE_TTL));
+
+        inner.touch(&key);
+        Some(packet)
+    }
+
+    /// Whether the answer to `question` is popular and about to expire,
+    /// so it should be fetched again now. Only the first caller is told so.
+    pub fn claim_prefetch(&self, question: &DnsQuestion) -> bool {
+        self.claim_prefetch_at(question, Instant::now())
+    }
+
+    fn claim_prefetch_at(&self, question: &DnsQuestion, now: Instant) -> bool {
+        if self.prefetch_hits == 0 {
+            return false;
+        }
+
+        let key = CacheKey::new(question);
+        let mut inner = self.inner.lock().unwrap();
+        let entry = match inner.entries.get_mut(&key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        // Within the last tenth of its lifetime, like other resolvers do.
+        let age = now.saturating_duration_since(entry.stored).as_secs();
+        let left = (entry.ttl as u64).saturating_sub(age);
+        if entry.prefetching
+            || entry.hits < self.prefetch_hits
+            || left == 0
+            || left * 10 > entry.ttl as u64
+        {
+            return false;
+        }
+
+        entry.prefetching = true;
+        true
+    }
+
+    /// Remember `response` as the answer to `question`, if it may be cached.
+    pub fn insert(&self, question: &DnsQuestion, response: &DnsPacket) {
+        self.insert_at(question, response, Instant::now())
+    }
+
+    fn insert_at(&self, question: &DnsQuestion, response: &DnsPacket, now: Instant) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let ttl = match self.cache_ttl(response) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        // The upstream OPT record only describes that one exchange.
+        let resources = response
+            .resources
+            .iter()
+            .filter(|record| !matches!(record, DnsRecord::OPT { .. }))
+            .cloned()
+            .collect();
+        let entry = CacheEntry {
+            rescode: response.header.rescode,
+            answers: response.answers.clone(),
+            authorities: response.authorities.clone(),
+            resources,
+            stored: now,
+            ttl,
+            used: 0,
+            hits: 0,
+            prefetching: false,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.add(CacheKey::new(question), entry, self.max_entries);
+    }
+
+    /// How long `response` may be cached for, if at all. Answers last as
+    /// long as their shortest TTL. Negative answers last as long as the SOA
+    /// of the zone says, and aren't cached without one (RFC 2308 5).
+    fn cache_ttl(&self, response: &DnsPacket) -> Option<u32> {
+        if response.header.truncated_message {
+            return None;
+        }
+
+        let negative = match response.header.rescode {
+            ResultCode::NXDOMAIN => true,
+            ResultCode::NOERROR => response.answers.is_empty(),
+            _ => return None,
+        };
+
+        if negative {
+            let soa_ttl = response
+                .authorities
+                .iter()
+                .find_map(|record| match record {
+                    DnsRecord::SOA { minimum, ttl, .. } => Some((*minimum).min(*ttl)),
+                    _ => None,
+                })?;
+            // Aliases leading to the missing name can't outlive it either.
+            let ttl = response
+                .answers
+                .iter()
+                .filter_map(|record| record.get_ttl())
+                .fold(soa_ttl, u32::min);
+            return Some(ttl.min(self.max_negative_ttl));
+        }
+
+        response
+            .answers
+            .iter()
+            .chain(response.authorities.iter())
+            .filter_map(|record| record.get_ttl())
+            .min()
+            .map(|ttl| ttl.min(self.max_ttl))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion::new(name.to_string(), QueryType::A, QueryClass::IN)
+    }
+
+    fn answer(name: &str, ttl: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: name.to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            class: QueryClass::IN,
+            ttl,
+        });
+        packet
+    }
+
+    fn negative(rescode: ResultCode, soa_ttl: u32, minimum: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = rescode;
+        packet.authorities.push(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            mname: "ns1.example.com".to_string(),
+            rname: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum,
+            class: QueryClass::IN,
+            ttl: soa_ttl,
+        });
+        packet
+    }
+
+    #[test]
+    fn ttls_count_down() {
+        let cache = Cache::new(10, 86400, 3600);
+        let now = Instant::now();
+        cache.insert_at(&question("example.com"), &answer("example.com", 300), now);
+
+        let cached = cache
+            .get_at(&question("EXAMPLE.com."), now + secs(100))
+            .unwrap();
+        assert_eq!(cached.answers[0].get_ttl(), Some(200));
+
+        assert!(cache
+            .get_at(&question("example.com"), now + secs(300))
+            .is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn negative_answers_use_the_soa() {
+        let cache = Cache::new(10, 86400, 3600);
+        let now = Instant::now();
+        cache.insert_at(
+            &question("nx.example.com"),
+            &negative(ResultCode::NXDOMAIN, 600, 60),
+            now,
+        );
+        cache.insert_at(
+            &question("nodata.example.com"),
+            &negative(ResultCode::NOERROR, 30, 900),
+            now,
+        );
+
+        let nx = cache
+            .get_at(&question("nx.example.com"), now + secs(59))
+            .unwrap();
+        assert_eq!(nx.header.rescode, ResultCode::NXDOMAIN);
+        assert!(cache
+            .get_at(&question("nx.example.com"), now + secs(60))
+            .is_none());
+        assert!(cache
+            .get_at(&question("nodata.example.com"), now + secs(30))
+            .is_none());
+
+        // Without an SOA there is nothing saying how long it's missing.
+        let mut bare = DnsPacket::new();
+        bare.header.rescode = ResultCode::NXDOMAIN;
+        cache.insert_at(&question("bare.example.com"), &bare, now);
+        assert!(cache.get_at(&question("bare.example.com"), now).is_none());
+    }
+
+    #[test]
+    fn failures_are_not_cached() {
+        let cache = Cache::new(10, 86400, 3600);
+        let mut failure = answer("example.com", 300);
+        failure.header.rescode = ResultCode::SERVFAIL;
+        cache.insert(&question("example.com"), &failure);
+
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let cache = Cache::new(2, 86400, 3600);
+        let now = Instant::now();
+        cache.insert_at(&question("a.com"), &answer("a.com", 300), now);
+        cache.insert_at(&question("b.com"), &answer("b.com", 300), now);
+        cache.get_at(&question("a.com"), now).unwrap();
+        cache.insert_at(&question("c.com"), &answer("c.com", 300), now);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_at(&question("a.com"), now).is_some());
+        assert!(cache.get_at(&question("b.com"), now).is_none());
+        assert!(cache.get_at(&question("c.com"), now).is_some());
+    }
+
+    #[test]
+    fn stale_answers_outlive_their_ttl() {
+        let cache = Cache::new(10, 86400, 3600).serve_stale(600);
+        let now = Instant::now();
+        cache.insert_at(&question("example.com"), &answer("example.com", 300), now);
+
+        assert!(cache
+            .get_at(&question("example.com"), now + secs(400))
+            .is_none());
+        let stale = cache
+            .get_stale_at(&question("example.com"), now + secs(400))
+            .unwrap();
+        assert_eq!(stale.answers[0].get_ttl(), Some(STALE_TTL));
+
+        assert!(cache
+            .get_stale_at(&question("example.com"), now + secs(900))
+            .is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn popular_answers_are_prefetched_once() {
+        let cache = Cache::new(10, 86400, 3600).prefetch(2);
+        let now = Instant::now();
+        cache.insert_at(&question("example.com"), &answer("example.com", 100), now);
+        cache.get_at(&question("example.com"), now).unwrap();
+
+        // Not popular enough yet, then not close enough to expiring.
+        assert!(!cache.claim_prefetch_at(&question("example.com"), now + secs(95)));
+        cache.get_at(&question("example.com"), now).unwrap();
+        assert!(!cache.claim_prefetch_at(&question("example.com"), now + secs(50)));
+
+        assert!(cache.claim_prefetch_at(&question("example.com"), now + secs(95)));
+        assert!(!cache.claim_prefetch_at(&question("example.com"), now + secs(96)));
+    }
+
+    #[test]
+    fn ttls_are_capped() {
+        let cache = Cache::new(10, 60, 3600);
+        let now = Instant::now();
+        cache.insert_at(&question("example.com"), &answer("example.com", 86400), now);
+
+        let cached = cache
+            .get_at(&question("example.com"), now + secs(10))
+            .unwrap();
+        assert_eq!(cached.answers[0].get_ttl(), Some(50));
+        assert!(cache
+            .get_at(&question("example.com"), now + secs(60))
+            .is_none());
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{Cache, CacheEntry, CacheKey};
+use crate::protocol::{
+    byte_packet_buffer::BytePacketBuffer, dns_packet::DnsPacket, dns_question::DnsQuestion, Result,
+};
+
+/// Start of every cache file, so other files and older layouts are refused.
+const MAGIC: &[u8; 8] = b"MINDNSC1";
+
+/// Seconds since the epoch, the one clock that carries over a restart.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads the fields of a cache file in order, failing on a short file.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err("Cache file is cut short".into());
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    /// The age, TTL and message of the next saved answer.
+    fn entry(&mut self) -> Result<(u32, u32, &'a [u8])> {
+        let age = self.u32()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        Ok((age, ttl, self.take(len)?))
+    }
+}
+
+/// The question and answer in a saved message.
+fn parse_entry(bytes: &[u8]) -> Result<(CacheKey, DnsPacket)> {
+    let mut buffer = BytePacketBuffer::from_bytes(bytes)?;
+    let packet = DnsPacket::from_buffer(&mut buffer)?;
+    match packet.questions.first() {
+        Some(question) => Ok((CacheKey::new(question), packet)),
+        None => Err("Cached answer without a question".into()),
+    }
+}
+
+impl Cache {
+    /// Write every answer to `path`, least recently used first. The file
+    /// holds when it was written, then for each answer its age and TTL in
+    /// seconds and the answer itself as a DNS message.
+    pub fn save(&self, path: &Path) -> Result<usize> {
+        self.save_at(path, Instant::now())
+    }
+
+    fn save_at(&self, path: &Path, now: Instant) -> Result<usize> {
+        let entries: Vec<(u32, u32, DnsPacket)> = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .lru
+                .values()
+                .filter_map(|key| {
+                    let entry = inner.entries.get(key)?;
+                    let age = now.saturating_duration_since(entry.stored).as_secs();
+                    let mut packet = entry.to_packet(|ttl| ttl);
+                    packet.questions.push(DnsQuestion::new(
+                        key.name.clone(),
+                        key.qtype,
+                        key.qclass,
+                    ));
+                    Some((age.min(u32::MAX as u64) as u32, entry.ttl, packet))
+                })
+                .collect()
+        };
+
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&unix_now().to_be_bytes());
+
+        let mut saved = 0;
+        for (age, ttl, mut packet) in entries {
+            let mut buffer = BytePacketBuffer::new();
+            if let Err(err) = packet.write(&mut buffer) {
+                log::debug!("Not saving cached answer: {}", err);
+                continue;
+            }
+            let bytes = buffer.as_bytes();
+
+            data.extend_from_slice(&age.to_be_bytes());
+            data.extend_from_slice(&ttl.to_be_bytes());
+            data.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            data.extend_from_slice(bytes);
+            saved += 1;
+        }
+
+        // Replace the old file in one step, so a crash halfway through
+        // leaves the previous snapshot intact.
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        fs::write(&temp, &data)?;
+        fs::rename(&temp, path)?;
+
+        Ok(saved)
+    }
+
+    /// Add the answers saved in `path`, minus the time that passed since.
+    /// Answers that expired in the meantime are left out, and so are those
+    /// that can't be read back.
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let data = fs::read(path)?;
+        let mut reader = Reader { data: &data };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("Not a mindns cache file".into());
+        }
+        let elapsed = unix_now().saturating_sub(reader.u64()?);
+
+        let now = Instant::now();
+        let mut loaded = 0;
+        let mut corrupt = 0;
+        let mut inner = self.inner.lock().unwrap();
+        while !reader.data.is_empty() {
+            // Past a cut short entry there is nothing left to read.
+            let (age, ttl, bytes) = match reader.entry() {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::debug!("Stopped reading cached answers: {}", err);
+                    corrupt += 1;
+                    break;
+                }
+            };
+            let (key, mut packet) = match parse_entry(bytes) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::debug!("Skipped cached answer: {}", err);
+                    corrupt += 1;
+                    continue;
+                }
+            };
+
+            let age = age as u64 + elapsed;
+            if age >= ttl as u64 {
+                continue;
+            }
+
+            // Start the clock over at the TTLs the answer has left.
+            let age = age as u32;
+            let records = packet
+                .answers
+                .iter_mut()
+                .chain(packet.authorities.iter_mut())
+                .chain(packet.resources.iter_mut());
+            for record in records {
+                if let Some(old) = record.get_ttl() {
+                    record.set_ttl(old.saturating_sub(age));
+                }
+            }
+
+            let entry = CacheEntry {
+                rescode: packet.header.rescode,
+                answers: packet.answers,
+                authorities: packet.authorities,
+                resources: packet.resources,
+                stored: now,
+                ttl: ttl - age,
+                used: 0,
+                hits: 0,
+                prefetching: false,
+            };
+            inner.add(key, entry, self.max_entries);
+            loaded += 1;
+        }
+
+        if corrupt > 0 {
+            log::warn!("Skipped {} corrupt cached answers in {:?}", corrupt, path);
+        }
+
+        Ok(loaded)
+    }
+
+    /// Save to `path` every `interval`, so a crash loses little.
+    pub fn spawn_saves(&self, interval: Duration, path: PathBuf) {
+        let cache = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                // Serialising and writing a large cache takes a while, keep
+                // it off the threads that answer queries.
+                let saving = {
+                    let cache = cache.clone();
+                    let path = path.clone();
+                    tokio::task::spawn_blocking(move || cache.save(&path))
+                };
+                match saving.await.unwrap_or_else(|err| Err(err.into())) {
+                    Ok(saved) => log::debug!("Saved {} cached answers", saved),
+                    Err(err) => log::warn!("Saving the cache to {:?} failed: {}", path, err),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::protocol::{dns_record::DnsRecord, query_class::QueryClass, query_type::QueryType};
+
+    #[test]
+    fn answers_survive_a_restart() {
+        let question =
+            |name: &str| DnsQuestion::new(name.to_string(), QueryType::A, QueryClass::IN);
+        let answer = |name: &str| {
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: name.to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                class: QueryClass::IN,
+                ttl: 300,
+            });
+            packet
+        };
+
+        // Saved 100 and 400 seconds after the answers came in.
+        let cache = Cache::new(10, 86400, 3600);
+        let start = Instant::now();
+        cache.insert_at(&question("expired.com"), &answer("expired.com"), start);
+        cache.insert_at(
+            &question("fresh.com"),
+            &answer("fresh.com"),
+            start + Duration::from_secs(300),
+        );
+
+        let path = std::env::temp_dir().join(format!("mindns-cache-{}.bin", std::process::id()));
+        cache
+            .save_at(&path, start + Duration::from_secs(400))
+            .unwrap();
+
+        // An unreadable answer in between and one cut short at the end
+        // only cost themselves.
+        let mut data = fs::read(&path).unwrap();
+        let header = MAGIC.len() + 8;
+        let bogus = [0, 0, 0, 0, 0, 0, 1, 44, 0, 3, 1, 2, 3];
+        data.splice(header..header, bogus);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        fs::write(&path, &data).unwrap();
+
+        let restored = Cache::new(10, 86400, 3600);
+        let loaded = restored.load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap(), 1);
+        let fresh = restored.get(&question("fresh.com")).unwrap();
+        assert!(fresh.answers[0].get_ttl().unwrap() <= 200);
+        assert!(restored.get(&question("expired.com")).is_none());
+    }
+}
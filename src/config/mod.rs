@@ -6,18 +6,233 @@ use serde_derive::Deserialize;
 pub struct ServerSettings {
     pub port: u16,
     pub bind: String,
+    #[serde(default = "default_tcp")]
+    pub tcp: bool,
+    #[serde(default = "default_tcp_timeout")]
+    pub tcp_timeout: u64,
+    /// TCP connections served at once, more wait to be accepted.
+    #[serde(default = "default_tcp_connections")]
+    pub tcp_connections: usize,
+    /// UDP requests handled at once, more wait in the socket buffer.
+    #[serde(default = "default_udp_workers")]
+    pub udp_workers: usize,
+}
+
+fn default_tcp() -> bool {
+    true
+}
+
+fn default_tcp_timeout() -> u64 {
+    10
+}
+
+fn default_tcp_connections() -> usize {
+    256
+}
+
+fn default_udp_workers() -> usize {
+    1024
+}
+
+/// How the upstream servers take turns answering queries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Always the first one, the others only when it fails.
+    #[default]
+    Failover,
+    RoundRobin,
+    Random,
+    /// The one with the lowest measured round trip time.
+    Fastest,
+}
+
+/// Where answers the rules don't give come from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Ask the upstream servers, which recurse for us.
+    #[default]
+    Forward,
+    /// Follow referrals down from the root servers ourselves.
+    Iterative,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct MirrorSettings {
     pub enabled: bool,
-    pub server: String,
+    #[serde(default)]
+    pub mode: Mode,
+    /// Root servers to start iterative resolution at, the built-in list
+    /// when empty.
+    #[serde(default)]
+    pub root_hints: Vec<String>,
+    #[serde(default = "default_qname_minimisation")]
+    pub qname_minimisation: bool,
+    /// Single upstream, as older configuration files have it.
+    #[serde(default)]
+    pub server: Option<String>,
+    #[serde(default)]
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default = "default_max_inflight")]
+    pub max_inflight: usize,
+    #[serde(default = "default_mirror_timeout")]
+    pub timeout: u64,
+    /// Seconds between health checks of every upstream, 0 turns them off.
+    #[serde(default = "default_health_check_interval")]
+    pub health_check_interval: u64,
+    #[serde(default = "default_health_check_name")]
+    pub health_check_name: String,
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "default_failure_cooldown")]
+    pub failure_cooldown: u64,
+}
+
+fn default_qname_minimisation() -> bool {
+    true
+}
+
+fn default_max_inflight() -> usize {
+    256
+}
+
+fn default_mirror_timeout() -> u64 {
+    5
+}
+
+fn default_health_check_interval() -> u64 {
+    30
+}
+
+fn default_health_check_name() -> String {
+    ".".to_string()
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_failure_cooldown() -> u64 {
+    30
+}
+
+/// Queries for `zone` and the names below it go to `servers` instead of
+/// the mirror.
+#[derive(Clone, Deserialize)]
+pub struct ForwardSettings {
+    pub zone: String,
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+/// Upstream answers kept in memory until their TTLs run out.
+#[derive(Clone, Deserialize)]
+pub struct CacheSettings {
+    #[serde(default = "default_cache_enabled")]
+    pub enabled: bool,
+    /// Answers kept at once, the least recently used ones go first.
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    #[serde(default = "default_cache_max_ttl")]
+    pub max_ttl: u32,
+    /// Upper bound on how long a missing name is remembered as missing.
+    #[serde(default = "default_cache_max_negative_ttl")]
+    pub max_negative_ttl: u32,
+    /// Answer with expired entries when no upstream answers (RFC 8767).
+    #[serde(default = "default_cache_serve_stale")]
+    pub serve_stale: bool,
+    /// Seconds past their expiry entries may still be served.
+    #[serde(default = "default_cache_max_stale_ttl")]
+    pub max_stale_ttl: u32,
+    /// Refresh popular entries shortly before they expire.
+    #[serde(default = "default_cache_prefetch")]
+    pub prefetch: bool,
+    /// Hits an entry needs to count as popular.
+    #[serde(default = "default_cache_prefetch_hits")]
+    pub prefetch_hits: u32,
+    /// File the cache is saved to and loaded from across restarts, kept
+    /// in memory only when unset.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Seconds between saves while running, 0 only saves on shutdown.
+    #[serde(default = "default_cache_save_interval")]
+    pub save_interval: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            enabled: default_cache_enabled(),
+            max_entries: default_cache_max_entries(),
+            max_ttl: default_cache_max_ttl(),
+            max_negative_ttl: default_cache_max_negative_ttl(),
+            serve_stale: default_cache_serve_stale(),
+            max_stale_ttl: default_cache_max_stale_ttl(),
+            prefetch: default_cache_prefetch(),
+            prefetch_hits: default_cache_prefetch_hits(),
+            path: None,
+            save_interval: default_cache_save_interval(),
+        }
+    }
+}
+
+fn default_cache_enabled() -> bool {
+    true
+}
+
+fn default_cache_max_entries() -> usize {
+    10000
+}
+
+fn default_cache_max_ttl() -> u32 {
+    86400
+}
+
+fn default_cache_max_negative_ttl() -> u32 {
+    3600
+}
+
+fn default_cache_serve_stale() -> bool {
+    true
+}
+
+fn default_cache_max_stale_ttl() -> u32 {
+    86400
+}
+
+fn default_cache_prefetch() -> bool {
+    true
+}
+
+fn default_cache_prefetch_hits() -> u32 {
+    3
+}
+
+fn default_cache_save_interval() -> u64 {
+    300
+}
+
+/// What to do about a line of a rules file that isn't a valid rule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Refuse to start, naming the file and line.
+    #[default]
+    Fail,
+    /// Leave the line out with a warning and load the rest.
+    Skip,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct RulesSettings {
     pub load_as: String,
     pub path: String,
+    #[serde(default)]
+    pub on_error: OnError,
 }
 
 #[derive(Clone, Deserialize)]
@@ -25,14 +240,45 @@ pub struct LoggingSettings {
     pub level: String,
     pub save_as: String,
     pub path: String,
+    /// Seconds between reports of upstream lookup counts, 0 turns them off.
+    #[serde(default = "default_stats_interval")]
+    pub stats_interval: u64,
+}
+
+fn default_stats_interval() -> u64 {
+    300
+}
+
+/// Built-in answers for CHAOS class queries (`version.bind`, `id.server`).
+#[derive(Clone, Default, Deserialize)]
+pub struct ChaosSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    pub version: Option<String>,
+    pub id: Option<String>,
+}
+
+impl ChaosSettings {
+    pub fn get_version(&self) -> String {
+        match self.version {
+            Some(ref version) => version.clone(),
+            None => format!("mindns {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
 }
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
     pub server: ServerSettings,
     pub mirror: MirrorSettings,
+    #[serde(default)]
+    pub forward: Vec<ForwardSettings>,
+    #[serde(default)]
+    pub cache: CacheSettings,
     pub rules: Vec<RulesSettings>,
     pub logs: LoggingSettings,
+    #[serde(default)]
+    pub chaos: ChaosSettings,
 }
 
 pub fn load_config(path: PathBuf) -> Config {
@@ -0,0 +1,174 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::watch;
+
+use crate::protocol::{
+    dns_packet::DnsPacket, dns_question::DnsQuestion, query_class::QueryClass,
+    query_type::QueryType, Result,
+};
+
+/// Outcome of a lookup as every waiter gets it. Errors can't be cloned, so
+/// they are passed on as their message.
+type Outcome = std::result::Result<DnsPacket, String>;
+
+type Key = (String, QueryType, QueryClass);
+
+fn key(question: &DnsQuestion) -> Key {
+    (
+        question.name.trim_end_matches('.').to_lowercase(),
+        question.qtype,
+        question.qclass,
+    )
+}
+
+/// Upstream lookups currently under way, so the same question asked again
+/// before the answer arrives waits for that answer instead of being sent
+/// a second time.
+pub struct Inflight {
+    lookups: Mutex<HashMap<Key, watch::Receiver<Option<Outcome>>>>,
+    sent: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+/// What a lookup has to do about its question.
+pub enum Flight {
+    /// Nobody asked yet, go ahead and tell the others when done.
+    Leader(Leader),
+    /// Somebody asked already, wait for their answer.
+    Follower(watch::Receiver<Option<Outcome>>),
+}
+
+impl Inflight {
+    pub fn new() -> Inflight {
+        Inflight {
+            lookups: Mutex::new(HashMap::new()),
+            sent: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    pub fn join(self: &Arc<Self>, question: &DnsQuestion) -> Flight {
+        let key = key(question);
+        let mut lookups = self.lookups.lock().unwrap();
+
+        if let Some(receiver) = lookups.get(&key) {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+            return Flight::Follower(receiver.clone());
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        lookups.insert(key.clone(), receiver);
+        self.sent.fetch_add(1, Ordering::Relaxed);
+
+        Flight::Leader(Leader {
+            inflight: self.clone(),
+            key,
+            sender,
+        })
+    }
+
+    /// Lookups sent upstream and lookups that waited on one of those
+    /// instead, since the start.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.sent.load(Ordering::Relaxed),
+            self.coalesced.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// The lookup others wait on. Dropping it without calling `finish` lets
+/// them know nothing is coming.
+pub struct Leader {
+    inflight: Arc<Inflight>,
+    key: Key,
+    sender: watch::Sender<Option<Outcome>>,
+}
+
+impl Leader {
+    pub fn finish(self, result: &Result<DnsPacket>) {
+        let outcome = match result {
+            Ok(response) => Ok(response.clone()),
+            Err(err) => Err(err.to_string()),
+        };
+        self.sender.send_replace(Some(outcome));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.inflight.lookups.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Wait for the lookup `receiver` belongs to and take its outcome.
+pub async fn wait(mut receiver: watch::Receiver<Option<Outcome>>) -> Result<DnsPacket> {
+    let outcome = match receiver.wait_for(Option::is_some).await {
+        Ok(outcome) => outcome.clone(),
+        Err(_) => None,
+    };
+
+    match outcome {
+        Some(Ok(response)) => Ok(response),
+        Some(Err(err)) => Err(err.into()),
+        None => Err("Lookup this one waited on was abandoned".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion::new(name.to_string(), QueryType::A, QueryClass::IN)
+    }
+
+    #[tokio::test]
+    async fn identical_questions_share_one_lookup() {
+        let inflight = Arc::new(Inflight::new());
+
+        let leader = match inflight.join(&question("example.com")) {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(_) => panic!("first lookup has to lead"),
+        };
+        let follower = match inflight.join(&question("EXAMPLE.com.")) {
+            Flight::Follower(receiver) => tokio::spawn(wait(receiver)),
+            Flight::Leader(_) => panic!("second lookup has to follow"),
+        };
+        assert!(matches!(
+            inflight.join(&question("other.com")),
+            Flight::Leader(_)
+        ));
+
+        let mut response = DnsPacket::new();
+        response.header.id = 1234;
+        leader.finish(&Ok(response));
+
+        assert_eq!(follower.await.unwrap().unwrap().header.id, 1234);
+        assert_eq!(inflight.stats(), (2, 1));
+        assert!(matches!(
+            inflight.join(&question("example.com")),
+            Flight::Leader(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn abandoned_lookups_fail_their_followers() {
+        let inflight = Arc::new(Inflight::new());
+
+        let leader = inflight.join(&question("example.com"));
+        let follower = match inflight.join(&question("example.com")) {
+            Flight::Follower(receiver) => tokio::spawn(wait(receiver)),
+            Flight::Leader(_) => panic!("second lookup has to follow"),
+        };
+        drop(leader);
+
+        assert!(follower.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use super::{in_zone, upstream::Upstreams};
+
+/// A zone whose queries go to its own upstream servers rather than the
+/// mirror, like `corp.internal` to the office resolver.
+pub struct ForwardZone {
+    /// Lowercase and without the trailing dot, empty for the root.
+    pub zone: String,
+    pub upstreams: Upstreams,
+}
+
+impl ForwardZone {
+    pub fn new(zone: &str, upstreams: Upstreams) -> ForwardZone {
+        ForwardZone {
+            zone: zone.trim_end_matches('.').to_lowercase(),
+            upstreams,
+        }
+    }
+
+    /// Whether `qname` is the zone itself or a name below it. Only whole
+    /// labels match, `notcorp.internal` is not in `corp.internal`.
+    pub fn contains(&self, qname: &str) -> bool {
+        in_zone(qname, &self.zone)
+    }
+}
+
+/// Sort zones so the most specific comes first, then the first one that
+/// contains a name is its longest matching suffix.
+pub fn sort_zones(zones: &mut [ForwardZone]) {
+    zones.sort_by_key(|zone| {
+        std::cmp::Reverse(match zone.zone.is_empty() {
+            true => 0,
+            false => zone.zone.split('.').count(),
+        })
+    });
+}
+
+/// The most specific zone `qname` falls in, if any.
+pub fn find_zone<'a>(zones: &'a [ForwardZone], qname: &str) -> Option<&'a ForwardZone> {
+    zones.iter().find(|zone| zone.contains(qname))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::Strategy;
+
+    fn zone(name: &str) -> ForwardZone {
+        let upstreams = Upstreams::new(Vec::new(), Strategy::Failover, 3, Duration::ZERO);
+        ForwardZone::new(name, upstreams)
+    }
+
+    #[test]
+    fn zone_matches_whole_labels() {
+        let corp = zone("Corp.Internal.");
+
+        assert!(corp.contains("corp.internal"));
+        assert!(corp.contains("host.corp.internal"));
+        assert!(corp.contains("HOST.CORP.INTERNAL."));
+        assert!(!corp.contains("notcorp.internal"));
+        assert!(!corp.contains("internal"));
+        assert!(zone(".").contains("anything.example"));
+    }
+
+    #[test]
+    fn longest_suffix_wins() {
+        let mut zones = vec![
+            zone("internal"),
+            zone("dev.corp.internal"),
+            zone("corp.internal"),
+        ];
+        sort_zones(&mut zones);
+
+        let found = |qname| find_zone(&zones, qname).map(|zone| zone.zone.as_str());
+        assert_eq!(found("a.dev.corp.internal"), Some("dev.corp.internal"));
+        assert_eq!(found("a.corp.internal"), Some("corp.internal"));
+        assert_eq!(found("a.other.internal"), Some("internal"));
+        assert_eq!(found("example.com"), None);
+    }
+}
@@ -0,0 +1,586 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use rand::seq::SliceRandom;
+
+use super::{in_zone, upstream::DEFAULT_PORT, Resolver};
+use crate::protocol::{
+    dns_packet::DnsPacket, dns_question::DnsQuestion, dns_record::DnsRecord,
+    query_class::QueryClass, query_type::QueryType, result_code::ResultCode, Result,
+};
+
+/// IPv4 addresses of a.root-servers.net to m.root-servers.net.
+pub const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Referrals followed for a single name before giving up.
+const MAX_REFERRALS: usize = 16;
+
+/// CNAME and DNAME records followed for a single question.
+const MAX_ALIASES: usize = 8;
+
+/// How deep lookups of name server addresses may nest.
+const MAX_DEPTH: usize = 3;
+
+/// Queries a single question may send in total, nested lookups included.
+const MAX_QUERIES: usize = 64;
+
+pub fn root_hints() -> Vec<SocketAddr> {
+    ROOT_HINTS
+        .iter()
+        .map(|ip| SocketAddr::new((*ip).into(), DEFAULT_PORT))
+        .collect()
+}
+
+/// The last `count` labels of `name`.
+fn suffix(name: &str, count: usize) -> &str {
+    let labels = name.split('.').count();
+    match name
+        .match_indices('.')
+        .nth(labels.saturating_sub(count + 1))
+    {
+        Some((i, _)) if count < labels => &name[i + 1..],
+        _ => name,
+    }
+}
+
+fn label_count(name: &str) -> usize {
+    match name {
+        "" => 0,
+        name => name.split('.').count(),
+    }
+}
+
+/// The zone `response` delegates `qname` to, if it is a referral. Only zones
+/// below `zone`, which the server is authoritative for, are accepted so it
+/// can't send us off to unrelated parts of the tree (bailiwick).
+fn referral(response: &DnsPacket, zone: &str, qname: &str) -> Option<String> {
+    if !response.answers.is_empty() || response.header.rescode != ResultCode::NOERROR {
+        return None;
+    }
+
+    response.authorities.iter().find_map(|record| match record {
+        DnsRecord::NS { domain, .. }
+            if !domain.eq_ignore_ascii_case(zone)
+                && in_zone(domain, zone)
+                && in_zone(qname, domain) =>
+        {
+            Some(domain.to_lowercase())
+        }
+        _ => None,
+    })
+}
+
+/// What following the CNAME and DNAME records in an answer led to.
+enum Chase {
+    /// Records of the asked type were found for the name.
+    Answered,
+    /// The name is an alias, the records for its target weren't included.
+    Aliased,
+    /// Nothing for the name at all.
+    Nothing,
+}
+
+/// Follow the aliases among `records` starting at `name`, moving the records
+/// that make up the chain to `chain` and `name` to where it ended up.
+fn chase(
+    records: &[DnsRecord],
+    name: &mut String,
+    qtype: QueryType,
+    chain: &mut Vec<DnsRecord>,
+    aliases: &mut usize,
+) -> Result<Chase> {
+    let mut result = Chase::Nothing;
+    loop {
+        let owned_by = |record: &&DnsRecord| {
+            record
+                .get_domain()
+                .is_some_and(|domain| domain.eq_ignore_ascii_case(name))
+        };
+
+        let answers: Vec<DnsRecord> = records
+            .iter()
+            .filter(owned_by)
+            .filter(|record| qtype == QueryType::UNKNOWN(255) || record.get_qtype() == qtype)
+            .cloned()
+            .collect();
+        if !answers.is_empty() {
+            chain.extend(answers);
+            return Ok(Chase::Answered);
+        }
+
+        if *aliases >= MAX_ALIASES {
+            return Err(format!("Too many aliases resolving {}", name).into());
+        }
+
+        // A DNAME moves everything below its owner to below its target, we
+        // hand the client the CNAME that implies as well (RFC 6672 3.1).
+        let dname = records.iter().find_map(|record| match record {
+            DnsRecord::DNAME {
+                domain,
+                host,
+                class,
+                ttl,
+            } if !domain.eq_ignore_ascii_case(name) && in_zone(name, domain) => {
+                Some((record, domain.len(), host.clone(), *class, *ttl))
+            }
+            _ => None,
+        });
+        if let Some((record, owner_len, target, class, ttl)) = dname {
+            let prefix = &name[..name.len() - owner_len];
+            let rewritten = format!("{}{}", prefix, target.trim_end_matches('.')).to_lowercase();
+            chain.push(record.clone());
+            chain.push(DnsRecord::CNAME {
+                domain: name.clone(),
+                host: rewritten.clone(),
+                class,
+                ttl,
+            });
+            *name = rewritten;
+            *aliases += 1;
+            result = Chase::Aliased;
+            continue;
+        }
+
+        let cname = records
+            .iter()
+            .filter(owned_by)
+            .find_map(|record| match record {
+                DnsRecord::CNAME { host, .. } => Some((record, host.to_lowercase())),
+                _ => None,
+            });
+        if let Some((record, target)) = cname {
+            chain.push(record.clone());
+            *name = target;
+            *aliases += 1;
+            result = Chase::Aliased;
+            continue;
+        }
+
+        return Ok(result);
+    }
+}
+
+impl Resolver {
+    /// Resolve a question from the root servers down, the way a recursive
+    /// resolver does, rather than asking someone else to do it.
+    pub(super) async fn iterate(&self, question: &DnsQuestion) -> Result<DnsPacket> {
+        let mut budget = MAX_QUERIES;
+        self.iterate_with(question, 0, &mut budget).await
+    }
+
+    async fn iterate_with(
+        &self,
+        question: &DnsQuestion,
+        depth: usize,
+        budget: &mut usize,
+    ) -> Result<DnsPacket> {
+        let mut name = question.name.trim_end_matches('.').to_lowercase();
+        let mut chain = Vec::new();
+        let mut aliases = 0;
+
+        loop {
+            let (response, zone) = self
+                .follow_referrals(&name, question.qtype, question.qclass, depth, budget)
+                .await?;
+
+            // Only trust what the server is authoritative for.
+            let answers: Vec<DnsRecord> = response
+                .answers
+                .iter()
+                .filter(|record| {
+                    record
+                        .get_domain()
+                        .is_some_and(|domain| in_zone(domain, &zone))
+                })
+                .cloned()
+                .collect();
+
+            let queried = name.clone();
+            match chase(
+                &answers,
+                &mut name,
+                question.qtype,
+                &mut chain,
+                &mut aliases,
+            )? {
+                Chase::Answered => {
+                    let mut packet = DnsPacket::new();
+                    packet.header.rescode = ResultCode::NOERROR;
+                    packet.answers = chain;
+                    return Ok(packet);
+                }
+                Chase::Aliased => {
+                    log::debug!("Following alias from {} to {}", queried, name);
+                    continue;
+                }
+                Chase::Nothing => {
+                    // No such name or no such type, the SOA in the authority
+                    // section says for how long.
+                    let mut packet = DnsPacket::new();
+                    packet.header.rescode = response.header.rescode;
+                    packet.answers = chain;
+                    packet.authorities = response
+                        .authorities
+                        .into_iter()
+                        .filter(|record| matches!(record, DnsRecord::SOA { domain, .. } if in_zone(domain, &zone)))
+                        .collect();
+                    return Ok(packet);
+                }
+            }
+        }
+    }
+
+    /// Walk down the delegations from the root to the servers that are
+    /// authoritative for `name` and return what they answer, along with
+    /// the zone they answered for.
+    async fn follow_referrals(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        qclass: QueryClass,
+        depth: usize,
+        budget: &mut usize,
+    ) -> Result<(DnsPacket, String)> {
+        let mut zone = String::new();
+        let mut servers = self.root_hints.as_ref().clone();
+        let labels = label_count(name);
+
+        // With QNAME minimisation each server only learns one label more
+        // than it needs to refer us onwards (RFC 9156).
+        let mut known = 1;
+        let mut referrals = 0;
+        loop {
+            let minimised = self.qname_minimisation && known < labels;
+            let question = match minimised {
+                true => DnsQuestion::new(suffix(name, known).to_string(), QueryType::A, qclass),
+                false => DnsQuestion::new(name.to_string(), qtype, qclass),
+            };
+
+            let response = self.query_servers(&servers, &question, budget).await?;
+
+            if let Some(child) = referral(&response, &zone, &question.name) {
+                referrals += 1;
+                if referrals > MAX_REFERRALS {
+                    return Err(format!("Too many referrals resolving {}", name).into());
+                }
+
+                servers = self
+                    .name_servers(&response, &zone, &child, depth, budget)
+                    .await?;
+                log::debug!("Referred to {} servers for {}", servers.len(), child);
+
+                known = label_count(&child) + 1;
+                zone = child;
+                continue;
+            }
+
+            // Nothing exists below a name that doesn't exist, so the full
+            // name doesn't either (RFC 9156 2.3).
+            if minimised && response.header.rescode == ResultCode::NXDOMAIN {
+                log::debug!("{} doesn't exist, so neither does {}", question.name, name);
+                return Ok((response, zone));
+            }
+
+            // The server answers for the shortened name itself, keep adding
+            // labels until it refers us on or we've asked the whole name.
+            if minimised {
+                known += 1;
+                continue;
+            }
+
+            return Ok((response, zone));
+        }
+    }
+
+    /// Addresses of the servers a referral to `child` names. Glue is only
+    /// taken from the referring server if the host is in its own zone,
+    /// anything else is looked up separately so it can't be poisoned.
+    async fn name_servers(
+        &self,
+        response: &DnsPacket,
+        zone: &str,
+        child: &str,
+        depth: usize,
+        budget: &mut usize,
+    ) -> Result<Vec<SocketAddr>> {
+        let hosts: Vec<&str> = response
+            .authorities
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { domain, host, .. } if domain.eq_ignore_ascii_case(child) => {
+                    Some(host.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+
+        let glue: Vec<SocketAddr> = response
+            .resources
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { domain, addr, .. }
+                    if hosts.iter().any(|host| host.eq_ignore_ascii_case(domain))
+                        && in_zone(domain, zone) =>
+                {
+                    Some(SocketAddr::new((*addr).into(), DEFAULT_PORT))
+                }
+                DnsRecord::AAAA { domain, addr, .. }
+                    if hosts.iter().any(|host| host.eq_ignore_ascii_case(domain))
+                        && in_zone(domain, zone) =>
+                {
+                    Some(SocketAddr::new((*addr).into(), DEFAULT_PORT))
+                }
+                _ => None,
+            })
+            .collect();
+        if !glue.is_empty() {
+            return Ok(glue);
+        }
+
+        if depth >= MAX_DEPTH {
+            return Err(format!("Name servers for {} nest too deep", child).into());
+        }
+
+        for host in hosts {
+            let question = DnsQuestion::new(host.to_string(), QueryType::A, QueryClass::IN);
+            let response = match Box::pin(self.iterate_with(&question, depth + 1, budget)).await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::debug!("Couldn't resolve name server {}: {}", host, err);
+                    continue;
+                }
+            };
+
+            let addrs: Vec<SocketAddr> = response
+                .answers
+                .iter()
+                .filter_map(|record| match record {
+                    DnsRecord::A { addr, .. } => {
+                        Some(SocketAddr::new((*addr).into(), DEFAULT_PORT))
+                    }
+                    _ => None,
+                })
+                .collect();
+            if !addrs.is_empty() {
+                return Ok(addrs);
+            }
+        }
+
+        Err(format!("No usable name server for {}", child).into())
+    }
+
+    /// Ask the servers of a zone one after the other until one of them
+    /// gives a usable answer.
+    async fn query_servers(
+        &self,
+        servers: &[SocketAddr],
+        question: &DnsQuestion,
+        budget: &mut usize,
+    ) -> Result<DnsPacket> {
+        let mut servers = servers.to_vec();
+        servers.shuffle(&mut rand::thread_rng());
+
+        for server in servers {
+            if *budget == 0 {
+                return Err(
+                    format!("Gave up on {} after {} queries", question.name, MAX_QUERIES).into(),
+                );
+            }
+            *budget -= 1;
+
+            log::debug!("Asking {} for {} {}", server, question.qtype, question.name);
+            match self.lookup(question, server, false).await {
+                Ok(response)
+                    if !matches!(
+                        response.header.rescode,
+                        ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP
+                    ) =>
+                {
+                    return Ok(response)
+                }
+                Ok(response) => {
+                    log::debug!("{} answered {:?}", server, response.header.rescode);
+                }
+                Err(err) => log::debug!("{} failed: {}", server, err),
+            }
+        }
+
+        Err(format!("No server answered {} {}", question.qtype, question.name).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::dns::tests::{fake_upstream, resolver};
+
+    fn a(domain: &str, ip: [u8; 4]) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: ip.into(),
+            class: QueryClass::IN,
+            ttl: 60,
+        }
+    }
+
+    fn alias(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            class: QueryClass::IN,
+            ttl: 60,
+        }
+    }
+
+    fn ns(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            class: QueryClass::IN,
+            ttl: 60,
+        }
+    }
+
+    #[test]
+    fn suffix_keeps_trailing_labels() {
+        assert_eq!(suffix("a.b.example.com", 1), "com");
+        assert_eq!(suffix("a.b.example.com", 2), "example.com");
+        assert_eq!(suffix("a.b.example.com", 4), "a.b.example.com");
+        assert_eq!(suffix("a.b.example.com", 9), "a.b.example.com");
+    }
+
+    #[test]
+    fn referrals_must_stay_in_bailiwick() {
+        let mut response = DnsPacket::new();
+        response
+            .authorities
+            .push(ns("example.com", "ns1.example.com"));
+
+        assert_eq!(
+            referral(&response, "com", "www.example.com").as_deref(),
+            Some("example.com")
+        );
+        // A com server can't speak for org, nor refer us back up.
+        assert_eq!(referral(&response, "org", "www.example.com"), None);
+        assert_eq!(referral(&response, "example.com", "www.example.com"), None);
+        assert_eq!(referral(&response, "com", "www.example.net"), None);
+    }
+
+    #[test]
+    fn aliases_are_chased() {
+        let records = [
+            alias("www.example.com", "web.example.com"),
+            alias("web.example.com", "cdn.example.com"),
+            a("cdn.example.com", [10, 0, 0, 1]),
+        ];
+        let mut name = "www.example.com".to_string();
+        let mut chain = Vec::new();
+        let mut aliases = 0;
+
+        let result = chase(&records, &mut name, QueryType::A, &mut chain, &mut aliases).unwrap();
+        assert!(matches!(result, Chase::Answered));
+        assert_eq!(chain, records);
+        assert_eq!(name, "cdn.example.com");
+    }
+
+    #[test]
+    fn dname_rewrites_the_name() {
+        let dname = DnsRecord::DNAME {
+            domain: "old.example".to_string(),
+            host: "new.example.".to_string(),
+            class: QueryClass::IN,
+            ttl: 60,
+        };
+        let mut name = "www.old.example".to_string();
+        let mut chain = Vec::new();
+        let mut aliases = 0;
+
+        let result = chase(
+            std::slice::from_ref(&dname),
+            &mut name,
+            QueryType::A,
+            &mut chain,
+            &mut aliases,
+        )
+        .unwrap();
+        assert!(matches!(result, Chase::Aliased));
+        assert_eq!(name, "www.new.example");
+        assert_eq!(chain, [dname, alias("www.old.example", "www.new.example")]);
+    }
+
+    #[test]
+    fn alias_loops_are_cut_short() {
+        let records = [
+            alias("a.example.com", "b.example.com"),
+            alias("b.example.com", "a.example.com"),
+        ];
+        let mut name = "a.example.com".to_string();
+        let mut chain = Vec::new();
+        let mut aliases = 0;
+
+        assert!(chase(&records, &mut name, QueryType::A, &mut chain, &mut aliases).is_err());
+    }
+
+    #[tokio::test]
+    async fn minimised_nxdomain_ends_the_lookup() {
+        static QUERIES: AtomicUsize = AtomicUsize::new(0);
+
+        // A root server that has never heard of anything.
+        let root = fake_upstream(
+            |_| {
+                QUERIES.fetch_add(1, Ordering::Relaxed);
+                let mut nxdomain = DnsPacket::new();
+                nxdomain.header.rescode = ResultCode::NXDOMAIN;
+                nxdomain.authorities.push(DnsRecord::SOA {
+                    domain: String::new(),
+                    mname: "a.root-servers.net".to_string(),
+                    rname: "nstld.verisign-grs.com".to_string(),
+                    serial: 1,
+                    refresh: 1800,
+                    retry: 900,
+                    expire: 604800,
+                    minimum: 86400,
+                    class: QueryClass::IN,
+                    ttl: 86400,
+                });
+                Some(nxdomain)
+            },
+            |_| None,
+        )
+        .await;
+
+        let resolver = resolver(root, 10, Duration::from_secs(2))
+            .iterative(&[root.to_string()], true)
+            .unwrap();
+        let question = DnsQuestion::new(
+            "www.example.invalid".to_string(),
+            QueryType::AAAA,
+            QueryClass::IN,
+        );
+        let response = resolver.resolve(None, &question).await.unwrap();
+
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        // Asked about invalid, never about example.invalid or the full name.
+        assert_eq!(QUERIES.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,86 +1,855 @@
-use std::net::{Ipv4Addr, UdpSocket};
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
 
-use crate::protocol::{
-    byte_packet_buffer::BytePacketBuffer, dns_packet::DnsPacket, dns_question::DnsQuestion,
-    query_type::QueryType, result_code::ResultCode, Result,
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::Semaphore,
+    time::timeout,
 };
 
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> Result<DnsPacket> {
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+use self::coalesce::{wait, Flight, Inflight};
+use self::forward::{find_zone, sort_zones, ForwardZone};
+use self::iterative::root_hints;
+use self::upstream::{parse_upstream, Upstream, Upstreams};
+use crate::{
+    config::{Config, Mode},
+    protocol::{
+        byte_packet_buffer::BytePacketBuffer,
+        dns_packet::{DnsPacket, EDNS_PAYLOAD_SIZE, UDP_MAX_SIZE},
+        dns_question::DnsQuestion,
+        dns_record::DnsRecord,
+        query_class::QueryClass,
+        query_type::QueryType,
+        result_code::ResultCode,
+        Error, Result,
+    },
+};
+
+pub mod coalesce;
+pub mod forward;
+pub mod iterative;
+pub mod upstream;
+
+/// How many random source ports to try before leaving it to the system.
+const PORT_ATTEMPTS: usize = 16;
 
+fn build_query(
+    id: u16,
+    question: &DnsQuestion,
+    recursion_desired: bool,
+    edns: bool,
+) -> Result<BytePacketBuffer> {
     let mut packet = DnsPacket::new();
 
-    packet.header.id = 6666;
+    packet.header.id = id;
     packet.header.questions = 1;
-    packet.header.recursion_desired = true;
-    packet
-        .questions
-        .push(DnsQuestion::new(qname.to_string(), qtype));
+    packet.header.recursion_desired = recursion_desired;
+    packet.questions.push(question.clone());
 
     let mut req_buffer = BytePacketBuffer::new();
+
+    // Tell the server how large an answer we can take, so it doesn't have to
+    // truncate anything that would fit.
+    if edns {
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: EDNS_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        });
+    }
+
     packet.write(&mut req_buffer)?;
-    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
 
-    let mut res_buffer = BytePacketBuffer::new();
-    // Add timeout to recv_from.
-    socket.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
-    socket.recv_from(&mut res_buffer.buf)?;
+    Ok(req_buffer)
+}
+
+/// Check that `response` answers the query we sent, so that a spoofed packet
+/// can't be slipped in its place (RFC 5452 3). Returns why it doesn't.
+fn check_response(
+    response: &DnsPacket,
+    id: u16,
+    query: &DnsQuestion,
+) -> std::result::Result<(), &'static str> {
+    if !response.header.response {
+        return Err("not a response");
+    }
+    if response.header.id != id {
+        return Err("id mismatch");
+    }
 
-    DnsPacket::from_buffer(&mut res_buffer)
+    match response.questions.as_slice() {
+        [question]
+            if question
+                .name
+                .eq_ignore_ascii_case(query.name.trim_end_matches('.'))
+                && question.qtype == query.qtype
+                && question.qclass == query.qclass =>
+        {
+            Ok(())
+        }
+        // Servers that can't parse the query may not echo the question.
+        [] if response.header.rescode == ResultCode::FORMERR => Ok(()),
+        _ => Err("question mismatch"),
+    }
 }
 
-pub fn recursive_lookup(dns_server: &str, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-    // For now we're always starting with *a.root-servers.net*.
-    let mut ns = dns_server.parse::<Ipv4Addr>().unwrap();
+/// Whether `name` is `zone` itself or a name below it, comparing whole
+/// labels only: `notexample.com` is not in `example.com`. The root zone is
+/// written as an empty string and contains everything.
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let zone = zone.trim_end_matches('.');
+    if zone.is_empty() || name.eq_ignore_ascii_case(zone) {
+        return true;
+    }
 
-    // Since it might take an arbitrary number of steps, we enter an unbounded loop.
-    loop {
-        log::info!("Attempting {:?} {} with ns {}", qtype, qname, ns);
+    name.len() > zone.len()
+        && name.as_bytes()[name.len() - zone.len() - 1] == b'.'
+        && name[name.len() - zone.len()..].eq_ignore_ascii_case(zone)
+}
 
-        // The next step is to send the query to the active server.
-        let ns_copy = ns;
+/// Bind a socket for talking to `server` on a random port, so the port is as
+/// hard to guess as the query id.
+async fn bind_random_port(server: SocketAddr) -> Result<UdpSocket> {
+    let ip = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
 
-        let server = (ns_copy, 53);
-        let response = lookup(qname, qtype, server)?;
+    for _ in 0..PORT_ATTEMPTS {
+        let port = rand::thread_rng().gen_range(1024..=u16::MAX);
+        match UdpSocket::bind(SocketAddr::new(ip, port)).await {
+            Ok(socket) => return Ok(socket),
+            Err(err) if err.kind() == ErrorKind::AddrInUse => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
 
-        // If there are entries in the answer section, and no errors, we are done!
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
-            return Ok(response);
+    // Port 0 still gets an ephemeral port the system picks for us.
+    Ok(UdpSocket::bind(SocketAddr::new(ip, 0)).await?)
+}
+
+/// Client for upstream servers. Every query gets its own socket on an
+/// ephemeral port, and clones share a limit on how many may be in flight.
+#[derive(Clone)]
+pub struct Resolver {
+    upstreams: Arc<Upstreams>,
+    zones: Arc<Vec<ForwardZone>>,
+    mode: Mode,
+    root_hints: Arc<Vec<SocketAddr>>,
+    qname_minimisation: bool,
+    slots: Arc<Semaphore>,
+    inflight: Arc<Inflight>,
+    timeout: Duration,
+}
+
+impl Resolver {
+    pub fn new(
+        upstreams: Upstreams,
+        mut zones: Vec<ForwardZone>,
+        max_inflight: usize,
+        timeout: Duration,
+    ) -> Resolver {
+        sort_zones(&mut zones);
+        Resolver {
+            upstreams: Arc::new(upstreams),
+            zones: Arc::new(zones),
+            mode: Mode::Forward,
+            root_hints: Arc::new(root_hints()),
+            qname_minimisation: true,
+            slots: Arc::new(Semaphore::new(max_inflight.max(1))),
+            inflight: Arc::new(Inflight::new()),
+            timeout,
         }
+    }
 
-        // We might also get a `NXDOMAIN` reply, which is the authoritative name servers
-        // way of telling us that the name doesn't exist.
-        if response.header.rescode == ResultCode::NXDOMAIN {
-            return Ok(response);
+    pub fn from_config(config: &Config) -> Result<Resolver> {
+        let settings = &config.mirror;
+        let upstreams = |servers: &[String], strategy| -> Result<Upstreams> {
+            let servers = servers
+                .iter()
+                .map(|server| parse_upstream(server))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Upstreams::new(
+                servers,
+                strategy,
+                settings.failure_threshold,
+                Duration::from_secs(settings.failure_cooldown),
+            ))
+        };
+
+        let mirror: Vec<String> = settings
+            .server
+            .iter()
+            .chain(settings.servers.iter())
+            .cloned()
+            .collect();
+        let zones = config
+            .forward
+            .iter()
+            .map(|forward| {
+                let upstreams = upstreams(&forward.servers, forward.strategy)?;
+                Ok(ForwardZone::new(&forward.zone, upstreams))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut resolver = Resolver::new(
+            upstreams(&mirror, settings.strategy)?,
+            zones,
+            settings.max_inflight,
+            Duration::from_secs(settings.timeout),
+        );
+        if settings.mode == Mode::Iterative {
+            resolver = resolver.iterative(&settings.root_hints, settings.qname_minimisation)?;
         }
 
-        // Otherwise, we'll try to find a new nameserver based on NS and a corresponding A
-        // record in the additional section. If this succeeds, we can switch name server
-        // and retry the loop.
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
+        Ok(resolver)
+    }
 
-            continue;
+    /// Resolve names without a forwarding zone ourselves, starting at the
+    /// root servers (or `root_hints` if any are given) instead of asking
+    /// the mirror.
+    pub fn iterative(
+        mut self,
+        root_hints: &[String],
+        qname_minimisation: bool,
+    ) -> Result<Resolver> {
+        if !root_hints.is_empty() {
+            let hints = root_hints
+                .iter()
+                .map(|hint| parse_upstream(hint))
+                .collect::<Result<Vec<_>>>()?;
+            self.root_hints = Arc::new(hints);
         }
+        self.mode = Mode::Iterative;
+        self.qname_minimisation = qname_minimisation;
 
-        // If not, we'll have to resolve the ip of a NS record. If no NS records exist,
-        // we'll go with what the last server told us.
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
-            None => return Ok(response),
+        Ok(self)
+    }
+
+    /// The forwarding zone with the longest suffix of `qname`, whose servers
+    /// answer it instead of the mirror.
+    pub fn forward_zone(&self, qname: &str) -> Option<&ForwardZone> {
+        find_zone(&self.zones, qname)
+    }
+
+    /// Answer a question through the upstream servers of `zone`, or the
+    /// mirror without one. Clients asking the same question at the same
+    /// time share a single lookup.
+    pub async fn resolve(
+        &self,
+        zone: Option<&ForwardZone>,
+        question: &DnsQuestion,
+    ) -> Result<DnsPacket> {
+        match self.inflight.join(question) {
+            Flight::Leader(leader) => {
+                let result = self.resolve_uncoalesced(zone, question).await;
+                leader.finish(&result);
+                result
+            }
+            Flight::Follower(receiver) => {
+                log::debug!(
+                    "Waiting on the lookup of {} {} already in flight",
+                    question.qtype,
+                    question.name
+                );
+                wait(receiver).await
+            }
+        }
+    }
+
+    /// Lookups sent upstream and lookups that shared one of those instead.
+    pub fn coalesce_stats(&self) -> (u64, u64) {
+        self.inflight.stats()
+    }
+
+    /// Answer a question on our own, moving on to the next server when one
+    /// can't be reached or fails to answer. In iterative mode questions
+    /// outside the forwarding zones are resolved from the root instead.
+    async fn resolve_uncoalesced(
+        &self,
+        zone: Option<&ForwardZone>,
+        question: &DnsQuestion,
+    ) -> Result<DnsPacket> {
+        let upstreams = match zone {
+            Some(zone) => &zone.upstreams,
+            None if self.mode == Mode::Iterative => return self.iterate(question).await,
+            None => self.upstreams.as_ref(),
         };
+        if upstreams.is_empty() {
+            return Err("No upstream servers configured".into());
+        }
+
+        let mut last = None;
+        for upstream in upstreams.order() {
+            let start = Instant::now();
+            // Upstreams are recursive resolvers, whatever they answer is
+            // the final answer.
+            let result = self.lookup(question, upstream.addr, true).await;
+
+            match result {
+                Ok(response)
+                    if matches!(
+                        response.header.rescode,
+                        ResultCode::SERVFAIL | ResultCode::REFUSED
+                    ) =>
+                {
+                    // The server is up, it just can't answer this question.
+                    // Only the lack of an answer takes it out of rotation,
+                    // or one bad zone could take out every server.
+                    upstream.record_rtt(start.elapsed());
+                    upstreams.record_success(upstream);
+                    log::warn!(
+                        "{} answered {:?} for {} {}, trying the next upstream",
+                        upstream.addr,
+                        response.header.rescode,
+                        question.qtype,
+                        question.name
+                    );
+                    last = Some(Ok(response));
+                }
+                Ok(response) => {
+                    upstream.record_rtt(start.elapsed());
+                    upstreams.record_success(upstream);
+                    return Ok(response);
+                }
+                Err(err) => {
+                    // Count a failure as slow as it gets, so the fastest
+                    // strategy stays away from dead servers.
+                    upstream.record_rtt(self.timeout);
+                    upstreams.record_failure(upstream);
+                    log::warn!(
+                        "{} failed for {} {}: {}, trying the next upstream",
+                        upstream.addr,
+                        question.qtype,
+                        question.name,
+                        err
+                    );
+                    last = Some(Err(err));
+                }
+            }
+        }
+
+        // Nobody to ask, answer right away instead of waiting on servers
+        // that are known to be down.
+        last.unwrap_or_else(|| Err("All upstream servers are down".into()))
+    }
+
+    /// Query every upstream for `name` each `interval`, so servers are taken
+    /// out of rotation before clients notice and put back once they recover.
+    /// Servers of a forwarding zone are asked about the zone itself.
+    pub fn spawn_health_checks(&self, interval: Duration, name: String) {
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let mirror = match resolver.mode {
+                    Mode::Forward => resolver.upstreams.all(),
+                    Mode::Iterative => &[],
+                };
+                for upstream in mirror {
+                    resolver
+                        .check_health(&resolver.upstreams, upstream, &name)
+                        .await;
+                }
+                for zone in resolver.zones.iter() {
+                    for upstream in zone.upstreams.all() {
+                        resolver
+                            .check_health(&zone.upstreams, upstream, &zone.zone)
+                            .await;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Report every `interval` how many lookups went upstream and how many
+    /// waited on an identical one instead, if there were any since last time.
+    pub fn spawn_stats_log(&self, interval: Duration) {
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            let mut last = (0, 0);
+            loop {
+                ticker.tick().await;
+                let stats = resolver.coalesce_stats();
+                if stats != last {
+                    resolver.log_stats();
+                    last = stats;
+                }
+            }
+        });
+    }
+
+    pub fn log_stats(&self) {
+        let (sent, coalesced) = self.coalesce_stats();
+        log::info!(
+            "Upstream lookups: {} sent, {} coalesced with one in flight",
+            sent,
+            coalesced
+        );
+
+        let zones = self.zones.iter().map(|zone| zone.upstreams.all());
+        for upstream in std::iter::once(self.upstreams.all()).chain(zones).flatten() {
+            log::info!(
+                "Upstream {} is {}, rtt {:?}, {} failures in a row",
+                upstream.addr,
+                if upstream.is_down() { "down" } else { "up" },
+                upstream.rtt(),
+                upstream.failures()
+            );
+        }
+    }
+
+    async fn check_health(&self, upstreams: &Upstreams, upstream: &Upstream, name: &str) {
+        let start = Instant::now();
+        let question = DnsQuestion::new(name.to_string(), QueryType::NS, QueryClass::IN);
+        let result = self.lookup(&question, upstream.addr, true).await;
+
+        match result {
+            // Like lookups, any answer means the server is up, even one
+            // that only says it can't resolve the name.
+            Ok(response) => {
+                if response.header.rescode != ResultCode::NOERROR
+                    && response.header.rescode != ResultCode::NXDOMAIN
+                {
+                    log::debug!(
+                        "Health check of {} answered {:?}",
+                        upstream.addr,
+                        response.header.rescode
+                    );
+                }
+                upstream.record_rtt(start.elapsed());
+                upstreams.record_success(upstream);
+            }
+            Err(err) => {
+                log::debug!("Health check of {} failed: {}", upstream.addr, err);
+                upstream.record_rtt(self.timeout);
+                upstreams.record_failure(upstream);
+            }
+        }
+
+        log::debug!(
+            "Upstream {} is {}, rtt {:?}",
+            upstream.addr,
+            if upstream.is_down() { "down" } else { "up" },
+            upstream.rtt()
+        );
+    }
+
+    /// Send one query to one server. Forwarded queries ask the server to
+    /// recurse for us, iterative ones don't.
+    async fn lookup(
+        &self,
+        question: &DnsQuestion,
+        server: SocketAddr,
+        recursion_desired: bool,
+    ) -> Result<DnsPacket> {
+        // Wait for a free slot rather than opening yet another socket.
+        let _permit = self.slots.acquire().await?;
+
+        let response = self
+            .lookup_udp(question, server, recursion_desired, true)
+            .await?;
+
+        // Servers that don't know about EDNS answer FORMERR, ask them again
+        // without it (RFC 6891 7).
+        if response.header.rescode == ResultCode::FORMERR && response.get_edns().is_none() {
+            log::debug!("{} doesn't support EDNS, retrying without it", server);
+            return self
+                .lookup_udp(question, server, recursion_desired, false)
+                .await;
+        }
+
+        Ok(response)
+    }
 
-        // Here we go down the rabbit hole by starting _another_ lookup sequence in the
-        // midst of our current one. Hopefully, this will give us the IP of an appropriate
-        // name server.
-        let recursive_response = recursive_lookup(dns_server, &new_ns_name, QueryType::A)?;
+    async fn lookup_udp(
+        &self,
+        question: &DnsQuestion,
+        server: SocketAddr,
+        recursion_desired: bool,
+        edns: bool,
+    ) -> Result<DnsPacket> {
+        let socket = bind_random_port(server).await?;
 
-        // Finally, we pick a random ip from the result, and restart the loop. If no such
-        // record is available, we again return the last result we got.
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = new_ns;
+        let id = rand::random::<u16>();
+        let req_buffer = build_query(id, question, recursion_desired, edns)?;
+        socket.send_to(req_buffer.as_bytes(), server).await?;
+
+        // Without EDNS the server must stay within the classic limit, with it
+        // we are only expecting what we advertised.
+        let mut data = if edns {
+            vec![0; EDNS_PAYLOAD_SIZE as usize]
         } else {
-            return Ok(response);
+            vec![0; UDP_MAX_SIZE]
+        };
+
+        // Anyone can send us a datagram, keep listening until the real
+        // answer shows up or we run out of time.
+        let receive = async {
+            loop {
+                let (size, from) = socket.recv_from(&mut data).await?;
+                if from != server {
+                    log::warn!(
+                        "Discarded response for {} from unexpected {}",
+                        question.name,
+                        from
+                    );
+                    continue;
+                }
+
+                let response = match BytePacketBuffer::from_bytes(&data[..size])
+                    .and_then(|mut res_buffer| DnsPacket::from_buffer(&mut res_buffer))
+                {
+                    Ok(response) => response,
+                    Err(err) => {
+                        log::warn!("Discarded malformed response from {}: {}", from, err);
+                        continue;
+                    }
+                };
+
+                match check_response(&response, id, question) {
+                    Ok(()) => return Ok::<_, Error>(response),
+                    Err(reason) => {
+                        log::warn!(
+                            "Discarded response for {} from {}: {}",
+                            question.name,
+                            from,
+                            reason
+                        )
+                    }
+                }
+            }
+        };
+        let response = match timeout(self.timeout, receive).await {
+            Ok(response) => response?,
+            Err(_) => return Err(format!("Timed out waiting for {}", server).into()),
+        };
+
+        // The answer didn't fit in a datagram, so the server handed us a partial
+        // packet. Ask again over TCP to get the whole thing.
+        if response.header.truncated_message {
+            log::debug!(
+                "Truncated response for {} {}, retrying over tcp",
+                question.qtype,
+                question.name
+            );
+            return self
+                .lookup_tcp(question, server, recursion_desired, edns)
+                .await;
         }
+
+        Ok(response)
+    }
+
+    async fn lookup_tcp(
+        &self,
+        question: &DnsQuestion,
+        server: SocketAddr,
+        recursion_desired: bool,
+        edns: bool,
+    ) -> Result<DnsPacket> {
+        let id = rand::random::<u16>();
+        let req_buffer = build_query(id, question, recursion_desired, edns)?;
+
+        let exchange = async {
+            let mut stream = TcpStream::connect(server).await?;
+
+            // Messages over TCP are prefixed with their length as a u16.
+            stream
+                .write_all(&(req_buffer.pos as u16).to_be_bytes())
+                .await?;
+            stream.write_all(req_buffer.as_bytes()).await?;
+
+            let len = stream.read_u16().await?;
+            let mut data = vec![0; len as usize];
+            stream.read_exact(&mut data).await?;
+
+            Ok::<_, std::io::Error>(data)
+        };
+        let data = match timeout(self.timeout, exchange).await {
+            Ok(data) => data?,
+            Err(_) => return Err(format!("Timed out waiting for {} over tcp", server).into()),
+        };
+
+        let mut res_buffer = BytePacketBuffer::from_bytes(&data)?;
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+
+        if let Err(reason) = check_response(&response, id, question) {
+            return Err(format!(
+                "Bad response for {} from {}: {}",
+                question.name, server, reason
+            )
+            .into());
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::Strategy;
+
+    /// How a fake upstream answers a query, `None` to stay silent.
+    pub(super) type Answer = fn(DnsPacket) -> Option<DnsPacket>;
+
+    fn encode(packet: &mut DnsPacket) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.as_bytes().to_vec()
+    }
+
+    /// Serve queries on a loopback port, over UDP with `udp` and over TCP
+    /// on the same port with `tcp`. Replies keep the id and question.
+    pub(super) async fn fake_upstream(udp: Answer, tcp: Answer) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).await.unwrap();
+
+        let reply = |query: DnsPacket, answer: Answer| {
+            let mut reply = DnsPacket::new();
+            reply.header.id = query.header.id;
+            reply.header.response = true;
+            reply.questions = query.questions.clone();
+            answer(query).map(|answer| {
+                reply.header.rescode = answer.header.rescode;
+                reply.header.truncated_message = answer.header.truncated_message;
+                reply.answers = answer.answers;
+                reply.authorities = answer.authorities;
+                reply.resources = answer.resources;
+                encode(&mut reply)
+            })
+        };
+
+        tokio::spawn(async move {
+            let mut data = [0; 4096];
+            loop {
+                let (size, from) = socket.recv_from(&mut data).await.unwrap();
+                let mut buffer = BytePacketBuffer::from_bytes(&data[..size]).unwrap();
+                let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+                if let Some(bytes) = reply(query, udp) {
+                    socket.send_to(&bytes, from).await.unwrap();
+                }
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap();
+                let mut data = vec![0; len as usize];
+                stream.read_exact(&mut data).await.unwrap();
+                let mut buffer = BytePacketBuffer::from_bytes(&data).unwrap();
+                let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+                if let Some(bytes) = reply(query, tcp) {
+                    stream.write_u16(bytes.len() as u16).await.unwrap();
+                    stream.write_all(&bytes).await.unwrap();
+                }
+            }
+        });
+
+        addr
+    }
+
+    pub(super) fn resolver(server: SocketAddr, max_inflight: usize, timeout: Duration) -> Resolver {
+        let upstreams =
+            Upstreams::new(vec![server], Strategy::Failover, 3, Duration::from_secs(30));
+        Resolver::new(upstreams, Vec::new(), max_inflight, timeout)
+    }
+
+    fn hosts(count: u8) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        for i in 0..count {
+            packet.answers.push(DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                class: QueryClass::IN,
+                ttl: 300,
+            });
+        }
+        packet
+    }
+
+    fn question(qname: &str, qtype: QueryType, qclass: QueryClass) -> DnsQuestion {
+        DnsQuestion::new(qname.to_string(), qtype, qclass)
+    }
+
+    fn response(id: u16, qname: &str) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.header.response = true;
+        packet
+            .questions
+            .push(question(qname, QueryType::A, QueryClass::IN));
+        packet
+    }
+
+    #[test]
+    fn matching_response_is_accepted() {
+        let packet = response(1234, "example.com");
+        let query = question("Example.COM.", QueryType::A, QueryClass::IN);
+
+        assert!(check_response(&packet, 1234, &query).is_ok());
+    }
+
+    #[test]
+    fn spoofed_responses_are_rejected() {
+        let packet = response(1234, "example.com");
+        let query = question("example.com", QueryType::A, QueryClass::IN);
+        assert!(check_response(&packet, 4321, &query).is_err());
+        for other in [
+            question("example.org", QueryType::A, QueryClass::IN),
+            question("example.com", QueryType::MX, QueryClass::IN),
+            question("example.com", QueryType::A, QueryClass::CH),
+        ] {
+            assert!(check_response(&packet, 1234, &other).is_err());
+        }
+
+        let mut not_response = response(1234, "example.com");
+        not_response.header.response = false;
+        assert!(check_response(&not_response, 1234, &query).is_err());
+
+        let mut empty = response(1234, "example.com");
+        empty.questions.clear();
+        assert!(check_response(&empty, 1234, &query).is_err());
+    }
+
+    #[test]
+    fn zones_match_whole_labels() {
+        assert!(in_zone("example.com", "example.com"));
+        assert!(in_zone("www.Example.COM.", "example.com."));
+        assert!(!in_zone("notexample.com", "example.com"));
+        assert!(!in_zone("com", "example.com"));
+        assert!(in_zone("example.com", ""));
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_asked_again_over_tcp() {
+        let server = fake_upstream(
+            |_| {
+                let mut partial = hosts(1);
+                partial.header.truncated_message = true;
+                Some(partial)
+            },
+            |_| Some(hosts(100)),
+        )
+        .await;
+
+        let response = resolver(server, 10, Duration::from_secs(2))
+            .resolve(None, &question("example.com", QueryType::A, QueryClass::IN))
+            .await
+            .unwrap();
+
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn servers_without_edns_are_asked_again_without_it() {
+        let server = fake_upstream(
+            |query| match query.get_edns() {
+                Some(_) => {
+                    let mut refused = DnsPacket::new();
+                    refused.header.rescode = ResultCode::FORMERR;
+                    Some(refused)
+                }
+                None => Some(hosts(1)),
+            },
+            |_| None,
+        )
+        .await;
+
+        let response = resolver(server, 10, Duration::from_secs(2))
+            .resolve(None, &question("example.com", QueryType::A, QueryClass::IN))
+            .await
+            .unwrap();
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_answers_keep_servers_up() {
+        let server = fake_upstream(
+            |_| {
+                let mut failed = DnsPacket::new();
+                failed.header.rescode = ResultCode::SERVFAIL;
+                Some(failed)
+            },
+            |_| None,
+        )
+        .await;
+
+        let resolver = resolver(server, 10, Duration::from_secs(2));
+        for _ in 0..5 {
+            let response = resolver
+                .resolve(None, &question("example.com", QueryType::A, QueryClass::IN))
+                .await
+                .unwrap();
+            assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+        }
+
+        let upstream = &resolver.upstreams.all()[0];
+        assert!(!upstream.is_down());
+        assert_eq!(upstream.failures(), 0);
+
+        // Health checks follow the same rule, answers never count as failures.
+        for _ in 0..5 {
+            resolver
+                .check_health(&resolver.upstreams, upstream, ".")
+                .await;
+        }
+        assert!(!upstream.is_down());
+        assert_eq!(upstream.failures(), 0);
+    }
+
+    #[tokio::test]
+    async fn silent_servers_time_out() {
+        let server = fake_upstream(|_| None, |_| None).await;
+        let timeout = Duration::from_millis(200);
+
+        let start = Instant::now();
+        let err = resolver(server, 10, timeout)
+            .resolve(None, &question("example.com", QueryType::A, QueryClass::IN))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().starts_with("Timed out"), "{}", err);
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < timeout * 5);
+    }
+
+    #[tokio::test]
+    async fn lookups_wait_for_a_free_slot() {
+        let server = fake_upstream(|_| None, |_| None).await;
+        let timeout = Duration::from_millis(200);
+
+        // Different names, so the lookups aren't coalesced into one.
+        let both = |resolver: Resolver| async move {
+            let start = Instant::now();
+            let a = question("a.example.com", QueryType::A, QueryClass::IN);
+            let b = question("b.example.com", QueryType::A, QueryClass::IN);
+            let (a, b) = tokio::join!(resolver.resolve(None, &a), resolver.resolve(None, &b));
+            assert!(a.is_err() && b.is_err());
+            start.elapsed()
+        };
+
+        // One slot, the second lookup only starts once the first timed out.
+        assert!(both(resolver(server, 1, timeout)).await >= timeout * 2);
+        assert!(both(resolver(server, 2, timeout)).await < timeout * 2);
     }
 }
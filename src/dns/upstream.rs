@@ -0,0 +1,269 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+use crate::{config::Strategy, protocol::Result};
+
+/// Port used for upstreams given without one.
+pub const DEFAULT_PORT: u16 = 53;
+
+/// Parse an upstream address: `1.1.1.1`, `1.1.1.1:5353`, `2606:4700::1111`
+/// or `[2606:4700::1111]:5353`.
+pub fn parse_upstream(text: &str) -> Result<SocketAddr> {
+    let text = text.trim();
+    if let Ok(addr) = text.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    let ip = text.trim_start_matches('[').trim_end_matches(']');
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, DEFAULT_PORT)),
+        Err(_) => Err(format!("Invalid upstream server address: {}", text).into()),
+    }
+}
+
+/// An upstream server, how quickly it has been answering and whether it
+/// is answering at all.
+pub struct Upstream {
+    pub addr: SocketAddr,
+    /// Smoothed round trip time in microseconds, 0 until first measured.
+    rtt: AtomicU64,
+    /// Failed queries since the last one that succeeded.
+    failures: AtomicU32,
+    /// Set while the server is considered down and skipped.
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    pub fn new(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr,
+            rtt: AtomicU64::new(0),
+            failures: AtomicU32::new(0),
+            down_until: Mutex::new(None),
+        }
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.down_until.lock().unwrap().is_some()
+    }
+
+    /// Whether queries may be sent to the server. A server that is down is
+    /// given another chance once its cool down has passed.
+    pub fn is_available(&self) -> bool {
+        match *self.down_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn rtt(&self) -> Duration {
+        Duration::from_micros(self.rtt.load(Ordering::Relaxed))
+    }
+
+    /// Fold a new measurement into the smoothed round trip time, giving it
+    /// a weight of 1/8 like TCP does (RFC 6298).
+    pub fn record_rtt(&self, sample: Duration) {
+        let sample = sample.as_micros().min(u64::MAX as u128) as u64;
+        // Lost updates from racing queries only cost a sample.
+        let old = self.rtt.load(Ordering::Relaxed);
+        let new = if old == 0 {
+            sample
+        } else {
+            (old * 7 + sample) / 8
+        };
+        self.rtt.store(new.max(1), Ordering::Relaxed);
+    }
+}
+
+/// The configured upstreams and the strategy used to pick between them.
+pub struct Upstreams {
+    servers: Vec<Upstream>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    /// Failures in a row after which a server is taken out of rotation.
+    failure_threshold: u32,
+    /// How long a server stays out before it is tried again.
+    cooldown: Duration,
+}
+
+impl Upstreams {
+    pub fn new(
+        servers: Vec<SocketAddr>,
+        strategy: Strategy,
+        failure_threshold: u32,
+        cooldown: Duration,
+    ) -> Upstreams {
+        Upstreams {
+            servers: servers.into_iter().map(Upstream::new).collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    pub fn all(&self) -> &[Upstream] {
+        &self.servers
+    }
+
+    pub fn record_success(&self, upstream: &Upstream) {
+        upstream.failures.store(0, Ordering::Relaxed);
+
+        let mut down_until = upstream.down_until.lock().unwrap();
+        if down_until.take().is_some() {
+            log::info!("Upstream {} is back up", upstream.addr);
+        }
+    }
+
+    /// Count a failed query, taking the server out of rotation once it has
+    /// failed too many times in a row. A server that fails the query it was
+    /// given after its cool down goes straight back out.
+    pub fn record_failure(&self, upstream: &Upstream) {
+        let failures = upstream.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.failure_threshold {
+            return;
+        }
+
+        let mut down_until = upstream.down_until.lock().unwrap();
+        if down_until.is_none() {
+            log::warn!(
+                "Upstream {} is down after {} failures in a row",
+                upstream.addr,
+                failures
+            );
+        }
+        *down_until = Some(Instant::now() + self.cooldown);
+    }
+
+    /// Every available upstream, in the order they should be tried for one
+    /// query. The ones after the first are only used if those before them
+    /// fail.
+    pub fn order(&self) -> Vec<&Upstream> {
+        let mut servers: Vec<&Upstream> = self
+            .servers
+            .iter()
+            .filter(|server| server.is_available())
+            .collect();
+        if servers.is_empty() {
+            return servers;
+        }
+
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % servers.len();
+                servers.rotate_left(start);
+            }
+            Strategy::Random => servers.shuffle(&mut rand::thread_rng()),
+            // Servers that haven't been measured yet sort first, so every
+            // one of them gets a chance to show how fast it is.
+            Strategy::Fastest => servers.sort_by_key(|server| server.rtt()),
+        }
+
+        servers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(60);
+
+    fn addrs(order: Vec<&Upstream>) -> Vec<SocketAddr> {
+        order.iter().map(|server| server.addr).collect()
+    }
+
+    #[test]
+    fn upstream_addresses_parse() {
+        assert_eq!(
+            parse_upstream("1.1.1.1").unwrap(),
+            "1.1.1.1:53".parse().unwrap()
+        );
+        assert_eq!(
+            parse_upstream("1.1.1.1:5353").unwrap(),
+            "1.1.1.1:5353".parse().unwrap()
+        );
+        assert_eq!(
+            parse_upstream("2606:4700::1111").unwrap(),
+            "[2606:4700::1111]:53".parse().unwrap()
+        );
+        assert_eq!(
+            parse_upstream("[2606:4700::1111]:5353").unwrap(),
+            "[2606:4700::1111]:5353".parse().unwrap()
+        );
+        assert!(parse_upstream("dns.example").is_err());
+    }
+
+    #[test]
+    fn strategies_order_servers() {
+        let servers: Vec<SocketAddr> = vec![
+            "10.0.0.1:53".parse().unwrap(),
+            "10.0.0.2:53".parse().unwrap(),
+            "10.0.0.3:53".parse().unwrap(),
+        ];
+
+        let failover = Upstreams::new(servers.clone(), Strategy::Failover, 3, COOLDOWN);
+        assert_eq!(addrs(failover.order()), servers);
+        assert_eq!(addrs(failover.order()), servers);
+
+        let round_robin = Upstreams::new(servers.clone(), Strategy::RoundRobin, 3, COOLDOWN);
+        assert_eq!(addrs(round_robin.order())[0], servers[0]);
+        assert_eq!(addrs(round_robin.order())[0], servers[1]);
+        assert_eq!(addrs(round_robin.order())[0], servers[2]);
+        assert_eq!(addrs(round_robin.order())[0], servers[0]);
+
+        let fastest = Upstreams::new(servers.clone(), Strategy::Fastest, 3, COOLDOWN);
+        fastest.servers[0].record_rtt(Duration::from_millis(30));
+        fastest.servers[1].record_rtt(Duration::from_millis(10));
+        assert_eq!(addrs(fastest.order()), [servers[2], servers[1], servers[0]]);
+        fastest.servers[2].record_rtt(Duration::from_millis(50));
+        assert_eq!(addrs(fastest.order()), [servers[1], servers[0], servers[2]]);
+    }
+
+    #[test]
+    fn failing_servers_are_skipped() {
+        let servers: Vec<SocketAddr> = vec![
+            "10.0.0.1:53".parse().unwrap(),
+            "10.0.0.2:53".parse().unwrap(),
+        ];
+        let upstreams = Upstreams::new(servers.clone(), Strategy::Failover, 2, COOLDOWN);
+
+        upstreams.record_failure(&upstreams.servers[0]);
+        assert_eq!(addrs(upstreams.order()), servers);
+
+        upstreams.record_failure(&upstreams.servers[0]);
+        assert!(upstreams.servers[0].is_down());
+        assert_eq!(addrs(upstreams.order()), [servers[1]]);
+
+        upstreams.record_success(&upstreams.servers[0]);
+        assert!(!upstreams.servers[0].is_down());
+        assert_eq!(addrs(upstreams.order()), servers);
+    }
+
+    #[test]
+    fn down_servers_are_retried_after_cool_down() {
+        let servers: Vec<SocketAddr> = vec!["10.0.0.1:53".parse().unwrap()];
+        let upstreams = Upstreams::new(servers.clone(), Strategy::Failover, 1, Duration::ZERO);
+
+        upstreams.record_failure(&upstreams.servers[0]);
+        assert!(upstreams.servers[0].is_down());
+        assert_eq!(addrs(upstreams.order()), servers);
+    }
+}
@@ -30,8 +30,8 @@ impl io::Write for DualWriter {
 
 unsafe impl Send for DualWriter {}
 
-impl Into<Box<dyn std::io::Write + Send>> for DualWriter {
-    fn into(self) -> Box<dyn std::io::Write + Send> {
-        Box::new(self)
+impl From<DualWriter> for Box<dyn std::io::Write + Send> {
+    fn from(writer: DualWriter) -> Self {
+        Box::new(writer)
     }
 }
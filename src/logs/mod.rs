@@ -2,6 +2,7 @@ use std::{
     fs::File,
     io::{self, Write},
     path::PathBuf,
+    str::FromStr,
 };
 
 use env_logger::fmt::Color;
@@ -37,8 +38,7 @@ pub fn get_log_path(settings: &LoggingSettings) -> Option<PathBuf> {
 pub fn get_log_file(settings: &LoggingSettings) -> Option<Box<File>> {
     let file_path = get_log_path(settings);
 
-    if file_path != None {
-        let file_path = file_path.unwrap();
+    if let Some(file_path) = file_path {
         let file = File::create(file_path).unwrap();
         Some(Box::new(file))
     } else {
@@ -53,7 +53,7 @@ pub fn stdout_target() -> Box<dyn io::Write + Send> {
 pub fn setup_logger(settings: &LoggingSettings) {
     let mut logger = env_logger::Builder::new();
     logger
-        .filter_level(LevelFilter::Info)
+        .filter_level(LevelFilter::from_str(&settings.level).unwrap_or(LevelFilter::Info))
         .format(|buf, record| {
             // Time color.
             let mut time_style = buf.style();
@@ -77,9 +77,8 @@ pub fn setup_logger(settings: &LoggingSettings) {
         });
 
     // Check if we should save logs to a file.
-    let file = get_log_file(&settings);
-    if file.is_some() {
-        let file = file.unwrap();
+    let file = get_log_file(settings);
+    if let Some(file) = file {
         let pipe: Box<dyn io::Write + Send> = DualWriter::new(file, stdout_target()).into();
         logger.target(env_logger::Target::Pipe(pipe)).init();
     } else {
@@ -1,12 +1,20 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
 use protocol::Result;
 
+use crate::cache::Cache;
 use crate::config::Config;
+use crate::dns::Resolver;
 use crate::logs::setup_logger;
-use crate::networking::handler::handle_request;
+use crate::networking::handler::{handle_request, Protocol};
+use crate::networking::tcp_serv::TcpServer;
 use crate::networking::udp_serv::UdpServer;
 use crate::protocol::byte_packet_buffer::BytePacketBuffer;
-use crate::rules::Rule;
+use crate::rules::index::RuleIndex;
 
+mod cache;
 mod config;
 mod dns;
 mod logs;
@@ -15,6 +23,9 @@ mod protocol;
 mod rules;
 mod utils;
 
+/// Everything a request is handled with, shared by all of them.
+type Context = Arc<(Config, RuleIndex, Resolver, Cache)>;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load configuration file.
@@ -23,30 +34,151 @@ async fn main() -> Result<()> {
     log::info!("Loaded configuration file.");
 
     // Load rules.
-    let rules = rules::parse_rules_config(&config.rules);
+    let rules = match rules::parse_rules_config(&config.rules).and_then(RuleIndex::new) {
+        Ok(rules) => rules,
+        Err(err) => {
+            log::error!("Loading rules failed: {}", err);
+            return Err(err);
+        }
+    };
     log::info!("Loaded {} rules.", rules.len());
 
+    // Upstream client shared by every request.
+    let resolver = Resolver::from_config(&config)?;
+    let forwarding = config.mirror.enabled || !config.forward.is_empty();
+    if forwarding && config.mirror.health_check_interval > 0 {
+        resolver.spawn_health_checks(
+            Duration::from_secs(config.mirror.health_check_interval),
+            config.mirror.health_check_name.clone(),
+        );
+    }
+
+    if config.logs.stats_interval > 0 {
+        resolver.spawn_stats_log(Duration::from_secs(config.logs.stats_interval));
+    }
+
+    // Answers shared by every request, kept across restarts if configured.
+    let cache = Cache::from_settings(&config.cache);
+    let cache_path = match config.cache.enabled {
+        true => config.cache.path.as_ref().map(PathBuf::from),
+        false => None,
+    };
+    if let Some(ref path) = cache_path {
+        if path.exists() {
+            match cache.load(path) {
+                Ok(loaded) => log::info!("Loaded {} cached answers.", loaded),
+                Err(err) => log::warn!("Loading the cache from {:?} failed: {}", path, err),
+            }
+        }
+        if config.cache.save_interval > 0 {
+            cache.spawn_saves(
+                Duration::from_secs(config.cache.save_interval),
+                path.clone(),
+            );
+        }
+    }
+
     // Start DNS server.
     let raw_addr = format!("{}:{}", config.server.bind, config.server.port);
     log::info!("Starting DNS server at udp://{}", raw_addr);
 
-    UdpServer::new(
-        raw_addr,
-        |peer, mut reader, (config, rules): (Config, Vec<Rule>)| async move {
-            let mut buffer = BytePacketBuffer::new();
-            while let Some(Ok(data)) = reader.recv().await {
-                buffer.pos = 0;
-                buffer.buf[..data.len()].copy_from_slice(&data);
+    let udp_server = UdpServer::new(
+        raw_addr.clone(),
+        |addr, data: Vec<u8>, context: Context| async move {
+            let (config, rules, resolver, cache) = &*context;
+            let mut buffer = BytePacketBuffer::from_bytes(&data)?;
+            handle_request(
+                config,
+                rules,
+                resolver,
+                cache,
+                &addr,
+                Protocol::Udp,
+                &mut buffer,
+            )
+            .await
+        },
+    )?
+    .set_max_workers(config.server.udp_workers)?;
+
+    let tcp_server = match config.server.tcp {
+        true => {
+            log::info!("Starting DNS server at tcp://{}", raw_addr);
+            let tcp_server = TcpServer::new(
+                raw_addr,
+                |addr, data: Vec<u8>, context: Context| async move {
+                    let (config, rules, resolver, cache) = &*context;
+                    let mut buffer = BytePacketBuffer::from_bytes(&data)?;
+                    handle_request(
+                        config,
+                        rules,
+                        resolver,
+                        cache,
+                        &addr,
+                        Protocol::Tcp,
+                        &mut buffer,
+                    )
+                    .await
+                },
+            )
+            .await?
+            .set_idle_timeout_sec(config.server.tcp_timeout)
+            .set_max_connections(config.server.tcp_connections)?;
+            Some(tcp_server)
+        }
+        false => None,
+    };
 
-                handle_request(&config, &rules, &peer, &mut buffer).await?;
+    let context = Arc::new((config, rules, resolver.clone(), cache.clone()));
+    let servers = async {
+        match tcp_server {
+            Some(tcp_server) => {
+                tokio::try_join!(udp_server.start(context.clone()), tcp_server.start(context))?;
+                Ok(())
             }
+            None => udp_server.start(context).await,
+        }
+    };
 
+    // A server that stops with an error still gets the cache saved before
+    // the error is returned.
+    let result = tokio::select! {
+        result = servers => result,
+        _ = shutdown_signal() => {
+            log::info!("Shutting down.");
             Ok(())
-        },
-    )?
-    .set_peer_timeout_sec(20)
-    .start((config, rules))
-    .await?;
+        }
+    };
+    resolver.log_stats();
+
+    if let Some(path) = cache_path {
+        match cache.save(&path) {
+            Ok(saved) => log::info!("Saved {} cached answers.", saved),
+            Err(err) => log::warn!("Saving the cache to {:?} failed: {}", path, err),
+        }
+    }
+
+    Ok(result?)
+}
+
+/// Resolves once the process is asked to stop, by Ctrl+C or by a service
+/// manager sending SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(err) => log::warn!("Can't listen for SIGTERM: {}", err),
+        }
+    }
 
-    Ok(())
+    let _ = tokio::signal::ctrl_c().await;
 }
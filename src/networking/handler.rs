@@ -1,30 +1,92 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::net::SocketAddr;
 
 use crate::{
-    config::Config,
-    dns::recursive_lookup,
+    cache::Cache,
+    config::{ChaosSettings, Config},
+    dns::Resolver,
     protocol::{
-        byte_packet_buffer::BytePacketBuffer, dns_packet::DnsPacket, dns_question::DnsQuestion,
-        dns_record::DnsRecord, result_code::ResultCode, Result,
+        byte_packet_buffer::{BytePacketBuffer, MAX_PACKET_SIZE},
+        dns_packet::{DnsPacket, EDNS_PAYLOAD_SIZE},
+        dns_question::DnsQuestion,
+        dns_record::DnsRecord,
+        query_class::QueryClass,
+        query_type::QueryType,
+        result_code::ResultCode,
+        Result,
     },
-    rules::{match_rule, Rule, A_APPEND, A_DENY},
+    rules::{index::RuleIndex, A_APPEND, A_DENY},
 };
 
-use super::peer::UdpPeer;
+/// Transport a request arrived on, which decides how large the reply may be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// Answer CHAOS class queries about the server itself, like `version.bind`.
+pub fn handle_chaos_query(settings: &ChaosSettings, question: &DnsQuestion, out: &mut DnsPacket) {
+    let value = match question.name.as_str() {
+        "version.bind" | "version.server" => Some(settings.get_version()),
+        "id.server" | "hostname.bind" => settings.id.clone(),
+        _ => None,
+    };
+
+    out.header.recursion_available = false;
+    out.header.authoritative_answer = true;
+
+    match value {
+        Some(value) if settings.enabled => {
+            if !matches!(question.qtype, QueryType::TXT) {
+                return;
+            }
+
+            match DnsRecord::from_text(&question.name, QueryType::TXT, QueryClass::CH, 0, &value) {
+                Ok(record) => out.answers.push(record),
+                Err(err) => {
+                    log::error!("Invalid chaos value for {}: {}", question.name, err);
+                    out.header.rescode = ResultCode::SERVFAIL;
+                }
+            }
+        }
+        _ => {
+            out.header.authoritative_answer = false;
+            out.header.rescode = ResultCode::REFUSED;
+        }
+    }
+}
 
 pub async fn handle_query(
     config: &Config,
-    rules: &Vec<Rule>,
+    rules: &RuleIndex,
+    resolver: &Resolver,
+    cache: &Cache,
     question: &DnsQuestion,
     out: &mut DnsPacket,
 ) {
-    // Try match rules.
-    let rule_matched = match_rule(rules, &question.name);
+    // CHAOS queries are about this server, they never reach the mirror.
+    if question.qclass == QueryClass::CH {
+        handle_chaos_query(&config.chaos, question, out);
+        return;
+    }
 
-    if rule_matched.is_some() {
-        let rule_matched = rule_matched.unwrap();
+    // Try match rules. Rules only describe internet names, questions in
+    // other classes go to the mirror as they are.
+    let rule_matched = match question.qclass {
+        QueryClass::IN | QueryClass::ANY => rules.find(&question.name),
+        _ => None,
+    };
 
+    if let Some(rule_matched) = rule_matched {
         match rule_matched.action {
+            // Exceptions leave the question to the upstreams.
+            _ if rule_matched.reverse => {
+                log::debug!(
+                    "Exception {} applies to {}",
+                    rule_matched.key,
+                    question.name
+                );
+            }
             A_DENY => {
                 out.header.rescode = ResultCode::NXDOMAIN;
                 return;
@@ -34,31 +96,71 @@ pub async fn handle_query(
                 out.header.recursion_desired = false;
                 out.header.recursion_available = false;
 
-                let raw_addr = rule_matched.value.unwrap_or("127.0.0.1".to_string());
+                // Rules for a specific type leave other types without answers.
+                let qtype = rule_matched.qtype.unwrap_or(QueryType::A);
+                if rule_matched.qtype.is_some() && qtype != question.qtype {
+                    return;
+                }
 
-                let domain = question.name.clone();
-                let addr = raw_addr.parse::<Ipv4Addr>().unwrap();
+                let value = rule_matched.value.as_deref().unwrap_or("127.0.0.1");
                 let ttl = 53000;
 
-                let record = DnsRecord::A { domain, addr, ttl };
-                out.answers.push(record);
+                match DnsRecord::from_text(&question.name, qtype, QueryClass::IN, ttl, value) {
+                    Ok(record) => out.answers.push(record),
+                    Err(err) => {
+                        log::error!("Invalid value for rule {}: {}", rule_matched.key, err);
+                        out.header.rescode = ResultCode::SERVFAIL;
+                    }
+                }
                 return;
             }
             _ => {}
         }
     }
 
-    // Try mirror.
-    let mirror_enabled = config.mirror.enabled;
-    let mirror_ns = config.mirror.server.as_str();
+    // Try the servers of a forwarding zone, then the mirror.
+    let zone = resolver.forward_zone(&question.name);
 
-    if mirror_enabled {
-        let result = recursive_lookup(mirror_ns, &question.name, question.qtype);
+    if zone.is_some() || config.mirror.enabled {
+        let result = match cache.get(question) {
+            Some(cached) => {
+                log::debug!("Answering {} {} from cache", question.qtype, question.name);
+                if cache.claim_prefetch(question) {
+                    spawn_prefetch(resolver, cache, question);
+                }
+                Ok(cached)
+            }
+            None => match resolver.resolve(zone, question).await {
+                Ok(response)
+                    if !matches!(
+                        response.header.rescode,
+                        ResultCode::SERVFAIL | ResultCode::REFUSED
+                    ) =>
+                {
+                    cache.insert(question, &response);
+                    Ok(response)
+                }
+                // Better an old answer than none at all (RFC 8767).
+                failed => match cache.get_stale(question) {
+                    Some(stale) => {
+                        log::warn!(
+                            "Upstreams failed for {} {}, answering from stale cache",
+                            question.qtype,
+                            question.name
+                        );
+                        Ok(stale)
+                    }
+                    None => failed,
+                },
+            },
+        };
 
-        if let Ok(result) = result {
-            out.header.rescode = result.header.rescode;
+        match result {
+            Ok(result) => {
+                out.header.rescode = result.header.rescode;
 
-            if result.header.rescode == ResultCode::NOERROR {
+                // Negative answers carry records too: the aliases that led
+                // to the missing name and the SOA saying how long it's missing.
                 for rec in result.answers {
                     out.answers.push(rec);
                 }
@@ -67,23 +169,62 @@ pub async fn handle_query(
                     out.authorities.push(rec);
                 }
 
+                // The upstream OPT record describes its connection to us, the
+                // client gets our own one instead.
                 for rec in result.resources {
-                    out.resources.push(rec);
+                    if !matches!(rec, DnsRecord::OPT { .. }) {
+                        out.resources.push(rec);
+                    }
                 }
             }
-        } else {
-            out.header.rescode = ResultCode::SERVFAIL;
+            Err(err) => {
+                log::warn!(
+                    "Lookup of {} {} failed: {}",
+                    question.qtype,
+                    question.name,
+                    err
+                );
+                out.header.rescode = ResultCode::SERVFAIL;
+            }
         }
     }
 }
 
+/// Look `question` up again in the background and cache the answer, so
+/// clients asking after the cached one expires don't wait for it.
+fn spawn_prefetch(resolver: &Resolver, cache: &Cache, question: &DnsQuestion) {
+    let resolver = resolver.clone();
+    let cache = cache.clone();
+    let question = question.clone();
+
+    tokio::spawn(async move {
+        let zone = resolver.forward_zone(&question.name);
+        match resolver.resolve(zone, &question).await {
+            Ok(response) => {
+                log::debug!("Prefetched {} {}", question.qtype, question.name);
+                cache.insert(&question, &response);
+            }
+            Err(err) => log::debug!(
+                "Prefetch of {} {} failed: {}",
+                question.qtype,
+                question.name,
+                err
+            ),
+        }
+    });
+}
+
 pub async fn handle_request(
     config: &Config,
-    rules: &Vec<Rule>,
-    peer: &Arc<UdpPeer>,
+    rules: &RuleIndex,
+    resolver: &Resolver,
+    cache: &Cache,
+    addr: &SocketAddr,
+    protocol: Protocol,
     buffer: &mut BytePacketBuffer,
-) -> Result<()> {
+) -> Result<Vec<u8>> {
     let mut request = DnsPacket::from_buffer(buffer)?;
+    let max_udp_size = request.max_udp_size();
 
     let mut packet = DnsPacket::new();
     packet.header.id = request.header.id;
@@ -91,26 +232,218 @@ pub async fn handle_request(
     packet.header.recursion_available = true;
     packet.header.response = true;
 
-    if let Some(question) = request.questions.pop() {
+    // Clients that speak EDNS get an OPT record back. We only implement
+    // version 0 and answer anything newer with BADVERS (RFC 6891 6.1.3).
+    let mut bad_version = false;
+    if let Some(DnsRecord::OPT {
+        version, dnssec_ok, ..
+    }) = request.get_edns()
+    {
+        bad_version = *version > 0;
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: EDNS_PAYLOAD_SIZE,
+            // Upper 8 bits of BADVERS (16), the header holds the lower 4.
+            extended_rcode: if bad_version { 1 } else { 0 },
+            version: 0,
+            // The DO bit is copied from the query (RFC 3225 3).
+            dnssec_ok: *dnssec_ok,
+            options: Vec::new(),
+        });
+    }
+
+    if bad_version {
+        log::debug!("Client {} sent an unsupported EDNS version", addr);
+    } else if request.questions.len() > 1 {
+        // There is no agreement on what several questions in one message
+        // would mean, so such messages are rejected as a whole (RFC 9619).
+        log::debug!(
+            "Client {} sent {} questions in one message",
+            addr,
+            request.questions.len()
+        );
+        packet.header.rescode = ResultCode::FORMERR;
+    } else if let Some(question) = request.questions.pop() {
         log::info!(
-            "Client {} requested {:?} {}",
-            peer.addr,
+            "Client {} requested {} {} {}",
+            addr,
+            question.qclass,
             question.qtype,
             question.name,
         );
 
         packet.questions.push(question.clone());
-        handle_query(config, rules, &question, &mut packet).await;
+        handle_query(config, rules, resolver, cache, &question, &mut packet).await;
     } else {
         packet.header.rescode = ResultCode::FORMERR;
     }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    packet.write(&mut res_buffer)?;
+    let max_size = match protocol {
+        Protocol::Udp => max_udp_size,
+        Protocol::Tcp => MAX_PACKET_SIZE,
+    };
+    let mut res_buffer = BytePacketBuffer::with_capacity(max_size);
+    packet.write_with_limit(&mut res_buffer, max_size)?;
+
+    Ok(res_buffer.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            rules = []
+
+            [server]
+            port = 53
+            bind = "127.0.0.1"
+
+            [mirror]
+            enabled = false
+
+            [logs]
+            level = "info"
+            save_as = "none"
+            path = ""
+
+            [chaos]
+            enabled = true
+            version = "mindns test"
+            id = "dns-1"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn query(name: &str, qtype: QueryType, qclass: QueryClass) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1234;
+        packet
+            .questions
+            .push(DnsQuestion::new(name.to_string(), qtype, qclass));
+        packet
+    }
 
-    let len = res_buffer.pos();
-    let data = res_buffer.get_range(0, len)?;
+    fn opt(version: u8, dnssec_ok: bool) -> DnsRecord {
+        DnsRecord::OPT {
+            packet_len: 4096,
+            extended_rcode: 0,
+            version,
+            dnssec_ok,
+            options: Vec::new(),
+        }
+    }
+
+    /// Send `request` through `handle_request` over UDP and parse the reply.
+    async fn ask(mut request: DnsPacket) -> DnsPacket {
+        let config = config();
+        let rules = RuleIndex::new(Vec::new()).unwrap();
+        let resolver = Resolver::from_config(&config).unwrap();
+        let cache = Cache::new(0, 0, 0);
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 5353));
+
+        let mut buffer = BytePacketBuffer::new();
+        request.write(&mut buffer).unwrap();
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_bytes()).unwrap();
+        let reply = handle_request(
+            &config,
+            &rules,
+            &resolver,
+            &cache,
+            &addr,
+            Protocol::Udp,
+            &mut buffer,
+        )
+        .await
+        .unwrap();
 
-    peer.send(data).await?;
-    Ok(())
+        let mut buffer = BytePacketBuffer::from_bytes(&reply).unwrap();
+        DnsPacket::from_buffer(&mut buffer).unwrap()
+    }
+
+    #[tokio::test]
+    async fn edns_is_answered_in_kind() {
+        let mut request = query("version.bind", QueryType::TXT, QueryClass::CH);
+        request.resources.push(opt(0, true));
+        let reply = ask(request).await;
+
+        assert_eq!(reply.header.rescode, ResultCode::NOERROR);
+        assert_eq!(reply.answers.len(), 1);
+        match reply.get_edns() {
+            Some(DnsRecord::OPT {
+                packet_len,
+                extended_rcode,
+                dnssec_ok,
+                ..
+            }) => {
+                assert_eq!(*packet_len, EDNS_PAYLOAD_SIZE);
+                assert_eq!(*extended_rcode, 0);
+                assert!(*dnssec_ok);
+            }
+            other => panic!("Expected an OPT record, got {:?}", other),
+        }
+
+        let reply = ask(query("version.bind", QueryType::TXT, QueryClass::CH)).await;
+        assert!(reply.get_edns().is_none());
+    }
+
+    #[tokio::test]
+    async fn newer_edns_versions_get_badvers() {
+        let mut request = query("version.bind", QueryType::TXT, QueryClass::CH);
+        request.resources.push(opt(1, false));
+        let reply = ask(request).await;
+
+        // BADVERS is 16, split between the OPT record and the header.
+        assert_eq!(reply.header.rescode, ResultCode::NOERROR);
+        assert!(reply.answers.is_empty());
+        match reply.get_edns() {
+            Some(DnsRecord::OPT {
+                extended_rcode,
+                version,
+                ..
+            }) => {
+                assert_eq!(*extended_rcode, 1);
+                assert_eq!(*version, 0);
+            }
+            other => panic!("Expected an OPT record, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn chaos_queries_describe_the_server() {
+        for (name, text) in [("version.bind", "mindns test"), ("id.server", "dns-1")] {
+            let reply = ask(query(name, QueryType::TXT, QueryClass::CH)).await;
+
+            assert_eq!(reply.header.rescode, ResultCode::NOERROR);
+            assert!(reply.header.authoritative_answer);
+            assert_eq!(reply.answers.len(), 1);
+            assert_eq!(
+                reply.answers[0].to_string(),
+                format!("{} 0 CH TXT \"{}\"", name, text)
+            );
+        }
+
+        let reply = ask(query("authors.bind", QueryType::TXT, QueryClass::CH)).await;
+        assert_eq!(reply.header.rescode, ResultCode::REFUSED);
+        assert!(reply.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn several_questions_are_a_format_error() {
+        let mut request = query("version.bind", QueryType::TXT, QueryClass::CH);
+        request.questions.push(DnsQuestion::new(
+            "id.server".to_string(),
+            QueryType::TXT,
+            QueryClass::CH,
+        ));
+        let reply = ask(request).await;
+
+        assert_eq!(reply.header.id, 1234);
+        assert_eq!(reply.header.rescode, ResultCode::FORMERR);
+        assert!(reply.answers.is_empty());
+    }
 }
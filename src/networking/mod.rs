@@ -1,3 +1,3 @@
 pub mod handler;
-pub mod peer;
+pub mod tcp_serv;
 pub mod udp_serv;
@@ -0,0 +1,334 @@
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, timeout};
+
+///How many queries a single connection may have in flight at the same time,
+///counting answers that haven't been written yet. Further messages are not
+///read from the socket until a slot is released
+pub const MAX_PIPELINED_QUERIES: usize = 32;
+
+///Number of connections served at once when none is configured
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+///Idle timeout used when none is configured
+pub const DEFAULT_IDLE_TIMEOUT_SEC: u64 = 10;
+
+///How long to wait before accepting again after accepting failed, so running
+///out of file descriptors doesn't turn into a busy loop
+pub const ACCEPT_BACKOFF_MS: u64 = 100;
+
+/// TCP Server listen
+/// messages are framed with a two byte length prefix (RFC 1035 4.2.2)
+pub struct TcpServer<I, T> {
+    listener: TcpListener,
+    input: Arc<I>,
+    _ph: PhantomData<T>,
+    idle_timeout: Option<Duration>,
+    max_connections: usize,
+}
+
+impl<I, R, T> TcpServer<I, T>
+where
+    I: Fn(SocketAddr, Vec<u8>, T) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Vec<u8>, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    T: Sync + Send + Clone + 'static,
+{
+    /// new tcp server
+    pub async fn new<A: ToSocketAddrs>(addr: A, input: I) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(TcpServer {
+            listener,
+            input: Arc::new(input),
+            _ph: Default::default(),
+            idle_timeout: Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SEC)),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        })
+    }
+
+    /// set how long a connection may stay without sending a message before it is closed,
+    /// 0 keeps idle connections open
+    #[inline]
+    pub fn set_idle_timeout_sec(mut self, sec: u64) -> TcpServer<I, T> {
+        self.idle_timeout = match sec {
+            0 => None,
+            sec => Some(Duration::from_secs(sec)),
+        };
+        self
+    }
+
+    /// set how many connections may be served at once, no more are accepted
+    /// while all of them are in use so they wait in the listen backlog.
+    /// with no connections nothing would ever be answered, so 0 is refused
+    #[inline]
+    pub fn set_max_connections(mut self, connections: usize) -> io::Result<TcpServer<I, T>> {
+        if connections == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tcp_connections must be at least 1",
+            ));
+        }
+        self.max_connections = connections;
+        Ok(self)
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// start server
+    pub async fn start(&self, inner: T) -> io::Result<()> {
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        loop {
+            let connection = match connections.clone().acquire_owned().await {
+                Ok(connection) => connection,
+                Err(_) => return Ok(()),
+            };
+            match self.listener.accept().await {
+                Ok((stream, addr)) => {
+                    log::trace!("accept tcp peer:{addr}");
+                    let inner = inner.clone();
+                    let input_fn = self.input.clone();
+                    let idle_timeout = self.idle_timeout;
+                    tokio::spawn(async move {
+                        if let Err(err) =
+                            handle_connection(stream, addr, input_fn, inner, idle_timeout).await
+                        {
+                            log::trace!("tcp peer:{addr} error:{err}");
+                        }
+                        drop(connection);
+                    });
+                }
+                Err(err) => {
+                    log::warn!("tcp accept error:{err}");
+                    sleep(Duration::from_millis(ACCEPT_BACKOFF_MS)).await;
+                }
+            }
+        }
+    }
+}
+
+/// Wait for `future`, or give up with `None` once `limit` has passed.
+async fn within<F: Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Read a single length prefixed message, returns `None` once the peer has
+/// closed the connection or stayed idle for too long.
+async fn read_message(
+    reader: &mut OwnedReadHalf,
+    idle_timeout: Option<Duration>,
+) -> io::Result<Option<Vec<u8>>> {
+    let len = match within(idle_timeout, reader.read_u16()).await {
+        Some(Ok(len)) => len as usize,
+        Some(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Some(Err(err)) => return Err(err),
+        None => return Ok(None),
+    };
+
+    let mut data = vec![0; len];
+    match within(idle_timeout, reader.read_exact(&mut data)).await {
+        Some(result) => result?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "incomplete message",
+            ))
+        }
+    };
+
+    Ok(Some(data))
+}
+
+/// Serve a single connection. Every message is handled on its own task so
+/// pipelined queries are answered as soon as they are ready, which may be
+/// out of order (RFC 7766 6.2.1.1).
+async fn handle_connection<I, R, T>(
+    stream: TcpStream,
+    addr: SocketAddr,
+    input: Arc<I>,
+    inner: T,
+    idle_timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    I: Fn(SocketAddr, Vec<u8>, T) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Vec<u8>, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    T: Sync + Send + Clone + 'static,
+{
+    let (mut reader, mut writer) = stream.into_split();
+    // Every answer carries the slot of its query, which is only released
+    // once the answer is written. A peer that doesn't read its answers
+    // runs out of slots and its socket isn't read any further.
+    let (tx, mut rx) = unbounded_channel::<(Vec<u8>, OwnedSemaphorePermit)>();
+
+    let write_task = tokio::spawn(async move {
+        while let Some((data, _permit)) = rx.recv().await {
+            // The length prefix can't say more than 65535.
+            let len = match u16::try_from(data.len()) {
+                Ok(len) => len,
+                Err(_) => {
+                    log::error!("tcp response of {} bytes is too long to send", data.len());
+                    continue;
+                }
+            };
+            writer.write_u16(len).await?;
+            writer.write_all(&data).await?;
+        }
+        writer.shutdown().await
+    });
+
+    let in_flight = Arc::new(Semaphore::new(MAX_PIPELINED_QUERIES));
+    while let Some(data) = read_message(&mut reader, idle_timeout).await? {
+        let permit = match in_flight.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+        // Writing failed, nothing more will reach the peer.
+        if tx.is_closed() {
+            break;
+        }
+        let tx = tx.clone();
+        let inner = inner.clone();
+        let input_fn = input.clone();
+        tokio::spawn(async move {
+            match (input_fn)(addr, data, inner).await {
+                Ok(response) => {
+                    if tx.send((response, permit)).is_err() {
+                        log::trace!("tcp peer:{addr} closed before response was sent");
+                    }
+                }
+                Err(err) => log::error!("tcp input error:{err}"),
+            }
+        });
+    }
+    log::trace!("close tcp peer:{addr}");
+
+    // Responses still being resolved keep their sender alive, so the writer
+    // only stops once every pending query has been answered.
+    drop(tx);
+    write_task.await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Start a server answering with `answer` on a free loopback port.
+    async fn serve<I, R>(answer: I) -> SocketAddr
+    where
+        I: Fn(SocketAddr, Vec<u8>, ()) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Vec<u8>, Box<dyn Error + Send + Sync>>> + Send + 'static,
+    {
+        let server = TcpServer::new("127.0.0.1:0", answer).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.start(()).await });
+        addr
+    }
+
+    async fn read_reply(stream: &mut TcpStream) -> Vec<u8> {
+        let len = stream.read_u16().await.unwrap();
+        let mut data = vec![0; len as usize];
+        stream.read_exact(&mut data).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn messages_are_length_prefixed() {
+        let addr = serve(|_, mut data: Vec<u8>, ()| async move {
+            data.reverse();
+            Ok(data)
+        })
+        .await;
+
+        // Two messages in one write, the second split from its prefix.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[0, 3, 1, 2, 3, 0, 2]).await.unwrap();
+        stream.write_all(&[4, 5]).await.unwrap();
+
+        assert_eq!(read_reply(&mut stream).await, [3, 2, 1]);
+        assert_eq!(read_reply(&mut stream).await, [5, 4]);
+    }
+
+    #[tokio::test]
+    async fn pipelined_queries_are_answered_when_ready() {
+        // The first byte says how long answering takes, in tens of ms.
+        let addr = serve(|_, data: Vec<u8>, ()| async move {
+            sleep(Duration::from_millis(data[0] as u64 * 10)).await;
+            Ok(data)
+        })
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[0, 2, 30, 1, 0, 2, 0, 2]).await.unwrap();
+
+        assert_eq!(read_reply(&mut stream).await, [0, 2]);
+        assert_eq!(read_reply(&mut stream).await, [30, 1]);
+    }
+
+    #[tokio::test]
+    async fn peers_that_dont_read_stop_being_read() {
+        static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+        // Every query gets a large answer, which the peer never reads.
+        let addr = serve(|_, _, ()| async move {
+            HANDLED.fetch_add(1, Ordering::Relaxed);
+            Ok(vec![0; 60000])
+        })
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let queries: Vec<u8> = [0, 1, 0].repeat(1000);
+        stream.write_all(&queries).await.unwrap();
+        sleep(Duration::from_millis(500)).await;
+
+        // What the socket buffers hold, plus the answers waiting on them.
+        let handled = HANDLED.load(Ordering::Relaxed);
+        assert!(handled < 500, "{} queries handled", handled);
+
+        // Reading the answers lets the rest through.
+        assert_eq!(read_reply(&mut stream).await.len(), 60000);
+    }
+
+    #[tokio::test]
+    async fn connections_wait_for_a_free_slot() {
+        let server = TcpServer::new(
+            "127.0.0.1:0",
+            |_, data: Vec<u8>, ()| async move { Ok(data) },
+        )
+        .await
+        .unwrap()
+        .set_max_connections(1)
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.start(()).await });
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(&[0, 1, 1]).await.unwrap();
+        assert_eq!(read_reply(&mut first).await, [1]);
+
+        // Connected, but not accepted until the first one is gone.
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(&[0, 1, 2]).await.unwrap();
+        let waiting = timeout(Duration::from_millis(200), read_reply(&mut second)).await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        assert_eq!(read_reply(&mut second).await, [2]);
+    }
+}
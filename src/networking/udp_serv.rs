@@ -1,5 +1,3 @@
-use async_lock::Mutex;
-use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::error::Error;
 use std::future::Future;
@@ -7,162 +5,119 @@ use std::io;
 use std::marker::PhantomData;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
-use std::time::Duration;
 
-use super::peer::{UDPPeer, UdpPeer, UdpReader};
 use net2::{UdpBuilder, UdpSocketExt};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::Semaphore;
 
-///The maximum size of a single UDP packet is 4096 by default. The MTU is generally not more than 1500 on the Internet
-///If the LAN is likely to be larger, 4096 is generally enough
-pub const BUFF_MAX_SIZE: usize = 4096;
+///Largest UDP payload there is, so no query is cut short however large
+///the EDNS payload size its sender advertises
+pub const BUFF_MAX_SIZE: usize = 65535;
 
-/// UDP Context
-/// each bind will create a
-pub struct UdpContext {
-    pub id: usize,
-    recv: Arc<UdpSocket>,
-    pub peers: Mutex<HashMap<SocketAddr, UDPPeer>>,
-}
-
-unsafe impl Send for UdpContext {}
-unsafe impl Sync for UdpContext {}
+///Number of datagrams handled at once when none is configured
+pub const DEFAULT_MAX_WORKERS: usize = 1024;
 
 /// UDP Server listen
+/// every datagram is a request of its own, there is no state kept per client
+/// and queries from the same client are answered in whatever order they finish
 pub struct UdpServer<I, T> {
-    udp_contexts: Vec<Arc<UdpContext>>,
+    sockets: Vec<Arc<UdpSocket>>,
     input: Arc<I>,
     _ph: PhantomData<T>,
-    clean_sec: Option<u64>,
+    max_workers: usize,
 }
 
 impl<I, R, T> UdpServer<I, T>
 where
-    I: Fn(UDPPeer, UdpReader, T) -> R + Send + Sync + 'static,
-    R: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static,
+    I: Fn(SocketAddr, Vec<u8>, T) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Vec<u8>, Box<dyn Error + Send + Sync>>> + Send + 'static,
     T: Sync + Send + Clone + 'static,
 {
     /// new udp server
     pub fn new<A: ToSocketAddrs>(addr: A, input: I) -> io::Result<Self> {
-        let udp_list = create_udp_socket_list(&addr, get_cpu_count())?;
-        let udp_contexts = udp_list
+        let sockets = create_udp_socket_list(&addr, get_cpu_count())?
             .into_iter()
-            .enumerate()
-            .map(|(id, socket)| {
-                Arc::new(UdpContext {
-                    id,
-                    recv: Arc::new(socket),
-                    peers: Default::default(),
-                })
-            })
+            .map(Arc::new)
             .collect();
         Ok(UdpServer {
-            udp_contexts,
+            sockets,
             input: Arc::new(input),
             _ph: Default::default(),
-            clean_sec: None,
+            max_workers: DEFAULT_MAX_WORKERS,
         })
     }
 
-    /// set how long the packet is not obtained and close the udp peer
+    /// set how many datagrams may be handled at once, the sockets aren't read
+    /// while all workers are busy so floods queue up in the kernel instead.
+    /// with no workers nothing would ever be answered, so 0 is refused
     #[inline]
-    pub fn set_peer_timeout_sec(mut self, sec: u64) -> UdpServer<I, T> {
-        assert!(sec > 0);
-        self.clean_sec = Some(sec);
-        self
+    pub fn set_max_workers(mut self, workers: usize) -> io::Result<UdpServer<I, T>> {
+        if workers == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp_workers must be at least 1",
+            ));
+        }
+        self.max_workers = workers;
+        Ok(self)
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sockets[0].local_addr()
     }
 
     /// start server
-    #[inline]
     pub async fn start(&self, inner: T) -> io::Result<()> {
-        let need_check_timeout = {
-            if let Some(clean_sec) = self.clean_sec {
-                let clean_sec = clean_sec as i64;
-                let contexts = self.udp_contexts.clone();
+        let workers = Arc::new(Semaphore::new(self.max_workers));
+
+        let listeners: Vec<_> = self
+            .sockets
+            .iter()
+            .enumerate()
+            .map(|(index, socket)| {
+                let socket = socket.clone();
+                let workers = workers.clone();
+                let input_fn = self.input.clone();
+                let inner = inner.clone();
                 tokio::spawn(async move {
+                    log::debug!("start udp listen:{index}");
+                    let mut buff = vec![0; BUFF_MAX_SIZE];
                     loop {
-                        let current = chrono::Utc::now().timestamp();
-                        for context in contexts.iter() {
-                            context.peers.lock().await.values().for_each(|peer| {
-                                if current - peer.get_last_recv_sec() > clean_sec {
-                                    peer.close();
-                                }
-                            });
-                        }
-                        tokio::time::sleep(Duration::from_secs(1)).await
-                    }
-                });
-                true
-            } else {
-                false
-            }
-        };
-
-        let (tx, mut rx) = unbounded_channel();
-        for (index, udp_listen) in self.udp_contexts.iter().enumerate() {
-            let create_peer_tx = tx.clone();
-            let udp_context = udp_listen.clone();
-            tokio::spawn(async move {
-                log::debug!("start udp listen:{index}");
-                let mut buff = [0; BUFF_MAX_SIZE];
-                loop {
-                    match udp_context.recv.recv_from(&mut buff).await {
-                        Ok((size, addr)) => {
-                            let peer = {
-                                udp_context
-                                    .peers
-                                    .lock()
-                                    .await
-                                    .entry(addr)
-                                    .or_insert_with(|| {
-                                        let (peer, reader) =
-                                            UdpPeer::new(index, udp_context.recv.clone(), addr);
-                                        log::trace!("create udp listen:{index} udp peer:{addr}");
-                                        if let Err(err) =
-                                            create_peer_tx.send((peer.clone(), reader, index, addr))
-                                        {
-                                            panic!("create_peer_tx err:{}", err);
-                                        }
-                                        peer
-                                    })
-                                    .clone()
-                            };
+                        let worker = match workers.clone().acquire_owned().await {
+                            Ok(worker) => worker,
+                            Err(_) => return,
+                        };
+                        let (size, addr) = match socket.recv_from(&mut buff).await {
+                            Ok(received) => received,
+                            Err(err) => {
+                                log::trace!("udp:{index} recv_from error:{err}");
+                                continue;
+                            }
+                        };
 
-                            if need_check_timeout {
-                                if let Err(err) = peer
-                                    .push_data_and_update_instant(buff[..size].to_vec())
-                                    .await
-                                {
-                                    log::error!("peer push data and update instant is error:{err}");
+                        let data = buff[..size].to_vec();
+                        let socket = socket.clone();
+                        let input_fn = input_fn.clone();
+                        let inner = inner.clone();
+                        tokio::spawn(async move {
+                            match (input_fn)(addr, data, inner).await {
+                                Ok(response) => {
+                                    if let Err(err) = socket.send_to(&response, addr).await {
+                                        log::trace!("udp peer:{addr} send error:{err}");
+                                    }
                                 }
-                            } else if let Err(err) = peer.push_data(buff[..size].to_vec()) {
-                                log::error!("peer push data is error:{err}");
+                                Err(err) => log::debug!("udp peer:{addr} error:{err}"),
                             }
-                        }
-                        Err(err) => {
-                            log::trace!("udp:{index} recv_from error:{err}");
-                        }
+                            drop(worker);
+                        });
                     }
-                }
-            });
-        }
-        drop(tx);
+                })
+            })
+            .collect();
 
-        while let Some((peer, reader, index, addr)) = rx.recv().await {
-            let inner = inner.clone();
-            let input_fn = self.input.clone();
-            let context = self
-                .udp_contexts
-                .get(index)
-                .expect("not found context")
-                .clone();
-            tokio::spawn(async move {
-                if let Err(err) = (input_fn)(peer, reader, inner).await {
-                    log::error!("udp input error:{err}")
-                }
-                context.peers.lock().await.remove(&addr);
-            });
+        for listener in listeners {
+            listener.await.map_err(io::Error::other)?;
         }
         Ok(())
     }
@@ -176,7 +131,7 @@ fn make_udp_client(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
     } else if addr.is_ipv6() {
         Ok(UdpBuilder::new_v6()?.reuse_address(true)?.bind(addr)?)
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, "not address AF_INET"))
+        Err(io::Error::other("not address AF_INET"))
     }
 }
 
@@ -195,7 +150,7 @@ fn make_udp_client(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
             .reuse_port(true)?
             .bind(addr)?)
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, "not address AF_INET"))
+        Err(io::Error::other("not address AF_INET"))
     }
 }
 
@@ -205,20 +160,12 @@ fn create_udp_socket<A: ToSocketAddrs>(addr: &A) -> io::Result<std::net::UdpSock
         let mut addrs = addr.to_socket_addrs()?;
         let addr = match addrs.next() {
             Some(addr) => addr,
-            None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "no socket addresses could be resolved",
-                ))
-            }
+            None => return Err(io::Error::other("no socket addresses could be resolved")),
         };
         if addrs.next().is_none() {
             Ok(addr)
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "more than one address resolved",
-            ))
+            Err(io::Error::other("more than one address resolved"))
         }
     };
     let res = make_udp_client(addr?)?;
@@ -259,3 +206,88 @@ fn get_cpu_count() -> usize {
 fn get_cpu_count() -> usize {
     1
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// Start a server with `workers` workers on a free loopback port, which
+    /// echoes datagrams and takes its time over those starting with `slow`.
+    async fn serve(workers: usize) -> SocketAddr {
+        let server = UdpServer::new("127.0.0.1:0", |_, data: Vec<u8>, ()| async move {
+            if data.starts_with(b"slow") {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+            }
+            Ok(data)
+        })
+        .unwrap()
+        .set_max_workers(workers)
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.start(()).await });
+        addr
+    }
+
+    async fn client(server: SocketAddr) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        socket
+    }
+
+    async fn reply(socket: &UdpSocket) -> Vec<u8> {
+        let mut data = vec![0; 512];
+        let size = socket.recv(&mut data).await.unwrap();
+        data.truncate(size);
+        data
+    }
+
+    #[tokio::test]
+    async fn workers_are_required() {
+        let server = UdpServer::new(
+            "127.0.0.1:0",
+            |_, data: Vec<u8>, ()| async move { Ok(data) },
+        )
+        .unwrap();
+        assert!(server.set_max_workers(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn queries_wait_while_every_worker_is_busy() {
+        let server = serve(2).await;
+        let (first, second, third) = (
+            client(server).await,
+            client(server).await,
+            client(server).await,
+        );
+
+        let start = Instant::now();
+        first.send(b"slow 1").await.unwrap();
+        second.send(b"slow 2").await.unwrap();
+        third.send(b"fast").await.unwrap();
+
+        // The third is read once a worker is free, not dropped.
+        assert_eq!(reply(&third).await, b"fast");
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(reply(&first).await, b"slow 1");
+        assert_eq!(reply(&second).await, b"slow 2");
+    }
+
+    #[tokio::test]
+    async fn slow_queries_dont_hold_up_the_same_client() {
+        let server = serve(8).await;
+        let socket = client(server).await;
+
+        let start = Instant::now();
+        socket.send(b"slow").await.unwrap();
+        socket.send(b"fast 1").await.unwrap();
+        socket.send(b"fast 2").await.unwrap();
+
+        // Answered as they finish, not in the order they were asked.
+        assert_eq!(reply(&socket).await, b"fast 1");
+        assert_eq!(reply(&socket).await, b"fast 2");
+        assert!(start.elapsed() < Duration::from_millis(250));
+        assert_eq!(reply(&socket).await, b"slow");
+    }
+}
@@ -1,36 +1,81 @@
+use std::collections::HashMap;
+
 use super::Result;
 
+/// Largest message that can be expressed at all, since TCP frames carry
+/// their length as a u16.
+pub const MAX_PACKET_SIZE: usize = 65535;
+
+/// Packet buffer that grows as it is written to, up to a fixed capacity.
+/// Reads are bounded by what has actually been written or loaded.
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
+    capacity: usize,
+    /// Where each name suffix written so far starts, so later names can
+    /// point back to it instead of repeating it (RFC 1035 4.1.4).
+    names: HashMap<String, usize>,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_capacity(MAX_PACKET_SIZE)
+    }
+
+    pub fn with_capacity(capacity: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: Vec::with_capacity(capacity.min(4096)),
             pos: 0,
+            capacity: capacity.min(MAX_PACKET_SIZE),
+            names: HashMap::new(),
+        }
+    }
+
+    /// Load a received message so it can be parsed. The buffer holds
+    /// exactly the message and can't grow past it.
+    pub fn from_bytes(data: &[u8]) -> Result<BytePacketBuffer> {
+        if data.len() > MAX_PACKET_SIZE {
+            return Err("Packet exceeds buffer capacity".into());
         }
+
+        let mut buffer = BytePacketBuffer::with_capacity(data.len());
+        buffer.buf.extend_from_slice(data);
+        Ok(buffer)
     }
 
     pub fn pos(&self) -> usize {
         self.pos
     }
 
-    pub fn step(&mut self, steps: usize) -> Result<()> {
-        self.pos += steps;
+    /// Everything written so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.pos.min(self.buf.len())]
+    }
 
-        Ok(())
+    pub fn step(&mut self, steps: usize) -> Result<()> {
+        self.seek(self.pos + steps)
     }
 
+    /// Move to `pos`, which may be anywhere up to the capacity. Positions
+    /// past the data are only useful for writing, reads there fail.
     pub fn seek(&mut self, pos: usize) -> Result<()> {
+        if pos > self.capacity {
+            return Err("Position past end of buffer".into());
+        }
         self.pos = pos;
 
         Ok(())
     }
 
+    /// Move back to `pos`, discarding everything written after it. Names
+    /// written past that point can no longer be pointed to.
+    pub fn rewind(&mut self, pos: usize) {
+        self.pos = pos;
+        self.names.retain(|_, offset| *offset < pos);
+    }
+
     pub fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         let res = self.buf[self.pos];
@@ -40,17 +85,17 @@ impl BytePacketBuffer {
     }
 
     pub fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(self.buf[pos])
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err("End of buffer".into());
         }
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..start + len])
     }
 
     pub fn read_u16(&mut self) -> Result<u16> {
@@ -60,6 +105,7 @@ impl BytePacketBuffer {
     }
 
     pub fn read_u32(&mut self) -> Result<u32> {
+        #[allow(clippy::identity_op)]
         let res = ((self.read()? as u32) << 24)
             | ((self.read()? as u32) << 16)
             | ((self.read()? as u32) << 8)
@@ -73,7 +119,7 @@ impl BytePacketBuffer {
         let mut jumped = false;
 
         let mut delim = "";
-        let max_jumps = 5;
+        let max_jumps = 32;
         let mut jumps_performed = 0;
         loop {
             // Dns Packets are untrusted data, so we need to be paranoid. Someone
@@ -129,9 +175,12 @@ impl BytePacketBuffer {
     }
 
     pub fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
+        if self.pos >= self.capacity {
             return Err("End of buffer".into());
         }
+        if self.pos >= self.buf.len() {
+            self.buf.resize(self.pos + 1, 0);
+        }
         self.buf[self.pos] = val;
         self.pos += 1;
         Ok(())
@@ -154,22 +203,58 @@ impl BytePacketBuffer {
         self.write(((val >> 24) & 0xFF) as u8)?;
         self.write(((val >> 16) & 0xFF) as u8)?;
         self.write(((val >> 8) & 0xFF) as u8)?;
+        #[allow(clippy::identity_op)]
         self.write(((val >> 0) & 0xFF) as u8)?;
 
         Ok(())
     }
 
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        for b in bytes {
+            self.write(*b)?;
+        }
+
+        Ok(())
+    }
+
     pub fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
+        self.write_name(qname, true)
+    }
+
+    /// Write a name in full even if it has been written before. Names in the
+    /// RDATA of newer record types must not be compressed (RFC 3597 4).
+    pub fn write_qname_uncompressed(&mut self, qname: &str) -> Result<()> {
+        self.write_name(qname, false)
+    }
+
+    fn write_name(&mut self, qname: &str, compress: bool) -> Result<()> {
+        // The root name is just the terminating empty label.
+        let labels: Vec<&str> = qname.split('.').filter(|label| !label.is_empty()).collect();
+
+        for (i, label) in labels.iter().enumerate() {
+            // If the rest of the name has been written before, point to it
+            // and we're done.
+            let suffix = labels[i..].join(".").to_lowercase();
+            if let Some(offset) = self.names.get(&suffix).filter(|_| compress) {
+                self.write_u16(0xC000 | *offset as u16)?;
+                return Ok(());
+            }
+
             let len = label.len();
-            if len > 0x34 {
+            if len > 0x3F {
                 return Err("Single label exceeds 63 characters of length".into());
             }
 
+            let pos = self.pos();
             self.write_u8(len as u8)?;
             for b in label.as_bytes() {
                 self.write_u8(*b)?;
             }
+
+            // Pointers only have 14 bits for the offset.
+            if pos <= 0x3FFF {
+                self.names.insert(suffix, pos);
+            }
         }
 
         self.write_u8(0)?;
@@ -178,6 +263,9 @@ impl BytePacketBuffer {
     }
 
     pub fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.buf.len() {
+            return Err("End of buffer".into());
+        }
         self.buf[pos] = val;
 
         Ok(())
@@ -190,3 +278,121 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_name(buffer: &mut BytePacketBuffer, pos: usize) -> String {
+        let mut name = String::new();
+        buffer.seek(pos).unwrap();
+        buffer.read_qname(&mut name).unwrap();
+        name
+    }
+
+    #[test]
+    fn repeated_name_is_a_pointer() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("www.example.com").unwrap();
+        let second = buffer.pos();
+        buffer.write_qname("www.example.com").unwrap();
+
+        assert_eq!(buffer.pos() - second, 2);
+        assert_eq!(buffer.as_bytes()[second..], [0xC0, 0x00]);
+        assert_eq!(read_name(&mut buffer, second), "www.example.com");
+    }
+
+    #[test]
+    fn shared_suffix_is_a_pointer() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("example.com").unwrap();
+        let second = buffer.pos();
+        buffer.write_qname("mail.example.com").unwrap();
+        let third = buffer.pos();
+        buffer.write_qname("smtp.mail.example.com").unwrap();
+        let fourth = buffer.pos();
+        buffer.write_qname("example.org").unwrap();
+
+        // One label plus a pointer to what came before.
+        assert_eq!(third - second, 1 + 4 + 2);
+        assert_eq!(fourth - third, 1 + 4 + 2);
+        assert_eq!(read_name(&mut buffer, 0), "example.com");
+        assert_eq!(read_name(&mut buffer, second), "mail.example.com");
+        assert_eq!(read_name(&mut buffer, third), "smtp.mail.example.com");
+        assert_eq!(read_name(&mut buffer, fourth), "example.org");
+    }
+
+    #[test]
+    fn compression_ignores_case() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("Example.COM").unwrap();
+        let second = buffer.pos();
+        buffer.write_qname("example.com").unwrap();
+
+        assert_eq!(buffer.pos() - second, 2);
+        assert_eq!(read_name(&mut buffer, second), "example.com");
+    }
+
+    #[test]
+    fn root_name_is_a_single_byte() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("").unwrap();
+
+        assert_eq!(buffer.as_bytes(), [0]);
+        assert_eq!(read_name(&mut buffer, 0), "");
+    }
+
+    #[test]
+    fn rewind_forgets_discarded_names() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("example.com").unwrap();
+        let mark = buffer.pos();
+        buffer.write_qname("other.net").unwrap();
+        buffer.rewind(mark);
+        buffer.write_qname("other.net").unwrap();
+
+        // Written in full again, rather than pointing at discarded bytes.
+        assert_eq!(buffer.pos() - mark, 11);
+        assert_eq!(read_name(&mut buffer, mark), "other.net");
+    }
+
+    #[test]
+    fn long_label_is_rejected() {
+        let mut buffer = BytePacketBuffer::new();
+        let label = "a".repeat(64);
+
+        assert!(buffer.write_qname(&label).is_err());
+        assert!(buffer.write_qname(&label[..63]).is_ok());
+    }
+
+    #[test]
+    fn buffers_grow_up_to_their_capacity() {
+        let mut buffer = BytePacketBuffer::with_capacity(4);
+        buffer.write_u32(0x01020304).unwrap();
+        assert!(buffer.write_u8(5).is_err());
+        assert_eq!(buffer.as_bytes(), [1, 2, 3, 4]);
+
+        assert!(buffer.seek(4).is_ok());
+        assert!(buffer.seek(5).is_err());
+        buffer.seek(1).unwrap();
+        assert!(buffer.step(4).is_err());
+        assert_eq!(buffer.pos(), 1);
+
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_bytes(&vec![0; MAX_PACKET_SIZE]).unwrap();
+        assert!(buffer.write_u8(0).is_err());
+    }
+
+    #[test]
+    fn loaded_messages_are_read_within_bounds() {
+        let mut buffer = BytePacketBuffer::from_bytes(&[0, 1, 2]).unwrap();
+        assert_eq!(buffer.read_u16().unwrap(), 1);
+        assert!(buffer.read_u16().is_err());
+        assert!(buffer.get_range(1, 3).is_err());
+        assert!(buffer.step(2).is_err());
+        assert!(buffer.write_u8(0).is_err());
+
+        assert!(BytePacketBuffer::from_bytes(&vec![0; MAX_PACKET_SIZE]).is_ok());
+        assert!(BytePacketBuffer::from_bytes(&vec![0; MAX_PACKET_SIZE + 1]).is_err());
+    }
+}
@@ -1,10 +1,15 @@
-use std::net::Ipv4Addr;
-
 use super::{
     byte_packet_buffer::BytePacketBuffer, dns_header::DnsHeader, dns_question::DnsQuestion,
-    dns_record::DnsRecord, query_type::QueryType, Result,
+    dns_record::DnsRecord, query_class::QueryClass, query_type::QueryType, Result,
 };
 
+/// Largest message a client accepts over UDP (RFC 1035 4.2.1).
+pub const UDP_MAX_SIZE: usize = 512;
+
+/// UDP payload size we advertise in our own OPT records, small enough to
+/// avoid IP fragmentation on most paths (DNS flag day 2020).
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -30,7 +35,11 @@ impl DnsPacket {
         result.header.read(buffer)?;
 
         for _ in 0..result.header.questions {
-            let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
+            let mut question = DnsQuestion::new(
+                "".to_string(),
+                QueryType::UNKNOWN(0),
+                QueryClass::UNKNOWN(0),
+            );
             question.read(buffer)?;
             result.questions.push(question);
         }
@@ -75,65 +84,266 @@ impl DnsPacket {
         Ok(())
     }
 
-    /// It's useful to be able to pick a random A record from a packet. When we
-    /// get multiple IP's for a single name, it doesn't matter which one we
-    /// choose, so in those cases we can now pick one at random.
-    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
-        self.answers.iter().find_map(|record| match record {
-            DnsRecord::A { addr, .. } => Some(*addr),
-            _ => None,
-        })
+    /// Write the packet without letting it grow past `max_size` bytes. Records
+    /// that don't fit are left out whole, and if anything from the answer or
+    /// authority sections had to go the message is flagged as truncated so
+    /// the client knows to retry over TCP.
+    pub fn write_with_limit(
+        &mut self,
+        buffer: &mut BytePacketBuffer,
+        max_size: usize,
+    ) -> Result<()> {
+        let start = buffer.pos();
+        if self.write(buffer).is_ok() && buffer.pos() - start <= max_size {
+            return Ok(());
+        }
+
+        // The OPT record has to survive truncation (RFC 6891 7), so set it
+        // aside and keep room for it at the end of the message.
+        let opt = self
+            .resources
+            .iter()
+            .position(|rec| matches!(rec, DnsRecord::OPT { .. }))
+            .map(|i| self.resources.remove(i));
+        let opt_size = match opt {
+            Some(ref opt) => opt.write(&mut BytePacketBuffer::new())?,
+            None => 0,
+        };
+        let max_size = max_size.saturating_sub(opt_size);
+
+        buffer.rewind(start);
+        self.header.write(buffer)?;
+        for question in &self.questions {
+            question.write(buffer)?;
+        }
+        if buffer.pos() - start > max_size {
+            return Err("Question section exceeds message size".into());
+        }
+
+        let answers = write_records_with_limit(&self.answers, buffer, start, max_size);
+        let authorities = if answers == self.answers.len() {
+            write_records_with_limit(&self.authorities, buffer, start, max_size)
+        } else {
+            0
+        };
+        let resources = if authorities == self.authorities.len() {
+            write_records_with_limit(&self.resources, buffer, start, max_size)
+        } else {
+            0
+        };
+
+        // Missing additional records don't need the flag (RFC 2181 9).
+        if answers < self.answers.len() || authorities < self.authorities.len() {
+            self.header.truncated_message = true;
+        }
+        self.answers.truncate(answers);
+        self.authorities.truncate(authorities);
+        self.resources.truncate(resources);
+
+        if let Some(opt) = opt {
+            opt.write(buffer)?;
+            self.resources.push(opt);
+        }
+
+        // Rewrite the header now that we know what made it in.
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+
+        let end = buffer.pos();
+        buffer.seek(start)?;
+        self.header.write(buffer)?;
+        buffer.seek(end)?;
+
+        Ok(())
     }
 
-    /// A helper function which returns an iterator over all name servers in
-    /// the authorities section, represented as (domain, host) tuples
-    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
-        self.authorities
+    /// The EDNS OPT pseudo-record from the additional section, if any.
+    pub fn get_edns(&self) -> Option<&DnsRecord> {
+        self.resources
             .iter()
-            // In practice, these are always NS records in well formed packages.
-            // Convert the NS records to a tuple which has only the data we need
-            // to make it easy to work with.
-            .filter_map(|record| match record {
-                DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
-                _ => None,
-            })
-            // Discard servers which aren't authoritative to our query
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .find(|rec| matches!(rec, DnsRecord::OPT { .. }))
     }
 
-    /// We'll use the fact that name servers often bundle the corresponding
-    /// A records when replying to an NS query to implement a function that
-    /// returns the actual IP for an NS record if possible.
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        // Get an iterator over the nameservers in the authorities section
-        self.get_ns(qname)
-            // Now we need to look for a matching A record in the additional
-            // section. Since we just want the first valid record, we can just
-            // build a stream of matching records.
-            .flat_map(|(_, host)| {
-                self.resources
-                    .iter()
-                    // Filter for A records where the domain match the host
-                    // of the NS record that we are currently processing
-                    .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
-                        _ => None,
-                    })
-            })
-            .map(|addr| *addr)
-            // Finally, pick the first valid entry
-            .next()
+    /// Largest UDP message we send back to the sender of this packet: the
+    /// payload size from its OPT record if it sent one, but never more than
+    /// we advertise ourselves, so a forged OPT can't have us send huge,
+    /// fragmented replies to whoever's address it carries.
+    pub fn max_udp_size(&self) -> usize {
+        match self.get_edns() {
+            Some(DnsRecord::OPT { packet_len, .. }) => {
+                (*packet_len as usize).clamp(UDP_MAX_SIZE, EDNS_PAYLOAD_SIZE as usize)
+            }
+            _ => UDP_MAX_SIZE,
+        }
     }
+}
+
+/// Write as many of `records` as fit before the message starting at `start`
+/// grows past `max_size` bytes, returning how many were written.
+fn write_records_with_limit(
+    records: &[DnsRecord],
+    buffer: &mut BytePacketBuffer,
+    start: usize,
+    max_size: usize,
+) -> usize {
+    for (written, rec) in records.iter().enumerate() {
+        let pos = buffer.pos();
+        if rec.write(buffer).is_err() || buffer.pos() - start > max_size {
+            buffer.rewind(pos);
+            return written;
+        }
+    }
+
+    records.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::protocol::dns_record::EdnsOption;
+
+    #[test]
+    fn compressed_packet_round_trips() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new(
+            "example.com".to_string(),
+            QueryType::MX,
+            QueryClass::IN,
+        ));
+        packet.answers.push(DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 300,
+        });
+        packet.authorities.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 300,
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: "mail.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            class: QueryClass::IN,
+            ttl: 300,
+        });
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+
+        // Only the question name and the first label of each host are
+        // spelled out, everything else is a pointer.
+        let uncompressed = 12 + (13 + 4) + (13 + 10 + 2 + 18) + (13 + 10 + 17) + (18 + 10 + 4);
+        assert!(buffer.pos() < uncompressed - 50);
+
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_bytes()).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(parsed.questions, packet.questions);
+        assert_eq!(parsed.answers, packet.answers);
+        assert_eq!(parsed.authorities, packet.authorities);
+        assert_eq!(parsed.resources, packet.resources);
+    }
+
+    #[test]
+    fn truncation_keeps_whole_records() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new(
+            "example.com".to_string(),
+            QueryType::A,
+            QueryClass::IN,
+        ));
+        for i in 0..100 {
+            packet.answers.push(DnsRecord::A {
+                domain: format!("host{}.example.com", i),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                class: QueryClass::IN,
+                ttl: 300,
+            });
+        }
+
+        let mut buffer = BytePacketBuffer::with_capacity(UDP_MAX_SIZE);
+        packet.write_with_limit(&mut buffer, UDP_MAX_SIZE).unwrap();
+        assert!(packet.header.truncated_message);
+        assert!(packet.answers.len() < 100);
+
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_bytes()).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(parsed.header.truncated_message);
+        assert_eq!(parsed.answers, packet.answers);
+    }
+
+    #[test]
+    fn only_missing_answers_set_the_flag() {
+        let host = |i: u8| DnsRecord::A {
+            domain: format!("host{}.example.com", i),
+            addr: Ipv4Addr::new(10, 0, 0, i),
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+        let mut packet = DnsPacket::new();
+        packet.answers.push(host(0));
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write_with_limit(&mut buffer, UDP_MAX_SIZE).unwrap();
+        assert!(!packet.header.truncated_message);
+        assert_eq!(packet.answers.len(), 1);
+
+        // Glue that doesn't fit is left out without the flag (RFC 2181 9).
+        packet.resources = (1..100).map(host).collect();
+        let mut buffer = BytePacketBuffer::new();
+        packet.write_with_limit(&mut buffer, UDP_MAX_SIZE).unwrap();
+        assert!(buffer.pos() <= UDP_MAX_SIZE);
+        assert!(!packet.header.truncated_message);
+        assert_eq!(packet.answers.len(), 1);
+        assert!(packet.resources.len() < 99);
+    }
+
+    #[test]
+    fn opt_records_round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1234;
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            extended_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        });
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_bytes()).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(parsed.resources, packet.resources);
+        assert_eq!(parsed.get_edns(), packet.get_edns());
+    }
+
+    #[test]
+    fn udp_size_stays_within_limits() {
+        let advertising = |packet_len| {
+            let mut packet = DnsPacket::new();
+            packet.resources.push(DnsRecord::OPT {
+                packet_len,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: false,
+                options: Vec::new(),
+            });
+            packet.max_udp_size()
+        };
 
-    /// However, not all name servers are as that nice. In certain cases there won't
-    /// be any A records in the additional section, and we'll have to perform *another*
-    /// lookup in the midst. For this, we introduce a method for returning the host
-    /// name of an appropriate name server.
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        // Get an iterator over the nameservers in the authorities section
-        self.get_ns(qname)
-            .map(|(_, host)| host)
-            // Finally, pick the first valid entry
-            .next()
+        assert_eq!(DnsPacket::new().max_udp_size(), UDP_MAX_SIZE);
+        assert_eq!(advertising(100), UDP_MAX_SIZE);
+        assert_eq!(advertising(1000), 1000);
+        assert_eq!(advertising(65535), EDNS_PAYLOAD_SIZE as usize);
     }
 }
@@ -1,20 +1,27 @@
-use super::{byte_packet_buffer::BytePacketBuffer, query_type::QueryType, Result};
+use super::{
+    byte_packet_buffer::BytePacketBuffer, query_class::QueryClass, query_type::QueryType, Result,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
+    pub qclass: QueryClass,
 }
 
 impl DnsQuestion {
-    pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
-        DnsQuestion { name, qtype }
+    pub fn new(name: String, qtype: QueryType, qclass: QueryClass) -> DnsQuestion {
+        DnsQuestion {
+            name,
+            qtype,
+            qclass,
+        }
     }
 
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
-        let _ = buffer.read_u16()?; // class
+        self.qclass = QueryClass::from_num(buffer.read_u16()?); // class
 
         Ok(())
     }
@@ -24,7 +31,7 @@ impl DnsQuestion {
 
         let typenum = self.qtype.to_num();
         buffer.write_u16(typenum)?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.qclass.to_num())?;
 
         Ok(())
     }
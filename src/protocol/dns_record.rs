@@ -1,58 +1,238 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
 
-use super::{byte_packet_buffer::BytePacketBuffer, query_type::QueryType, Result};
+use super::{
+    byte_packet_buffer::BytePacketBuffer, query_class::QueryClass, query_type::QueryType, Result,
+};
 
+/// A single key/value parameter of an SVCB or HTTPS record.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-#[allow(dead_code)]
+pub struct SvcParam {
+    pub key: u16,
+    pub value: Vec<u8>,
+}
+
+/// A single option carried in the RDATA of an OPT record.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
+        class: QueryClass,
         ttl: u32,
     }, // 0
     A {
         domain: String,
         addr: Ipv4Addr,
+        class: QueryClass,
         ttl: u32,
     }, // 1
     NS {
         domain: String,
         host: String,
+        class: QueryClass,
         ttl: u32,
     }, // 2
     CNAME {
         domain: String,
         host: String,
+        class: QueryClass,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        class: QueryClass,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: String,
+        host: String,
+        class: QueryClass,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
         host: String,
+        class: QueryClass,
         ttl: u32,
     }, // 15
+    TXT {
+        domain: String,
+        data: Vec<Vec<u8>>,
+        class: QueryClass,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
+        class: QueryClass,
         ttl: u32,
     }, // 28
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        class: QueryClass,
+        ttl: u32,
+    }, // 33
+    DNAME {
+        domain: String,
+        host: String,
+        class: QueryClass,
+        ttl: u32,
+    }, // 39
+    OPT {
+        packet_len: u16,
+        extended_rcode: u8,
+        version: u8,
+        dnssec_ok: bool,
+        options: Vec<EdnsOption>,
+    }, // 41
+    SVCB {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<SvcParam>,
+        class: QueryClass,
+        ttl: u32,
+    }, // 64
+    HTTPS {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<SvcParam>,
+        class: QueryClass,
+        ttl: u32,
+    }, // 65
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    }, // 257
 }
 
 impl DnsRecord {
+    /// The owner name of the record, OPT records don't have a real one.
+    pub fn get_domain(&self) -> Option<&str> {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::DNAME { domain, .. }
+            | DnsRecord::SVCB { domain, .. }
+            | DnsRecord::HTTPS { domain, .. }
+            | DnsRecord::CAA { domain, .. } => Some(domain),
+            DnsRecord::OPT { .. } => None,
+        }
+    }
+
+    /// Time to live in seconds, OPT records don't have one.
+    pub fn get_ttl(&self) -> Option<u32> {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::CAA { ttl, .. } => Some(*ttl),
+            DnsRecord::OPT { .. } => None,
+        }
+    }
+
+    pub fn set_ttl(&mut self, value: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::CAA { ttl, .. } => *ttl = value,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn get_qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::DNAME { .. } => QueryType::DNAME,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::SVCB { .. } => QueryType::SVCB,
+            DnsRecord::HTTPS { .. } => QueryType::HTTPS,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+        }
+    }
+
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
         let mut domain = String::new();
         buffer.read_qname(&mut domain)?;
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let raw_class = buffer.read_u16()?;
+        let class = QueryClass::from_num(raw_class);
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
+        let end = buffer.pos() + data_len as usize;
 
-        match qtype {
+        let record: Result<DnsRecord> = match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
+                #[allow(clippy::identity_op)]
                 let addr = Ipv4Addr::new(
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
@@ -60,13 +240,19 @@ impl DnsRecord {
                     ((raw_addr >> 0) & 0xFF) as u8,
                 );
 
-                Ok(DnsRecord::A { domain, addr, ttl })
+                Ok(DnsRecord::A {
+                    domain,
+                    addr,
+                    class,
+                    ttl,
+                })
             }
             QueryType::AAAA => {
                 let raw_addr1 = buffer.read_u32()?;
                 let raw_addr2 = buffer.read_u32()?;
                 let raw_addr3 = buffer.read_u32()?;
                 let raw_addr4 = buffer.read_u32()?;
+                #[allow(clippy::identity_op)]
                 let addr = Ipv6Addr::new(
                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
                     ((raw_addr1 >> 0) & 0xFFFF) as u16,
@@ -78,7 +264,12 @@ impl DnsRecord {
                     ((raw_addr4 >> 0) & 0xFFFF) as u16,
                 );
 
-                Ok(DnsRecord::AAAA { domain, addr, ttl })
+                Ok(DnsRecord::AAAA {
+                    domain,
+                    addr,
+                    class,
+                    ttl,
+                })
             }
             QueryType::NS => {
                 let mut ns = String::new();
@@ -87,6 +278,7 @@ impl DnsRecord {
                 Ok(DnsRecord::NS {
                     domain,
                     host: ns,
+                    class,
                     ttl,
                 })
             }
@@ -97,6 +289,18 @@ impl DnsRecord {
                 Ok(DnsRecord::CNAME {
                     domain,
                     host: cname,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::DNAME => {
+                let mut target = String::new();
+                buffer.read_qname(&mut target)?;
+
+                Ok(DnsRecord::DNAME {
+                    domain,
+                    host: target,
+                    class,
                     ttl,
                 })
             }
@@ -109,20 +313,169 @@ impl DnsRecord {
                     domain,
                     priority,
                     host: mx,
+                    class,
                     ttl,
                 })
             }
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                buffer.read_qname(&mut ptr)?;
+
+                Ok(DnsRecord::PTR {
+                    domain,
+                    host: ptr,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::TXT => {
+                // One or more length prefixed character strings.
+                let mut data = Vec::new();
+                while buffer.pos() < end {
+                    let len = buffer.read()? as usize;
+                    data.push(buffer.get_range(buffer.pos(), len)?.to_vec());
+                    buffer.step(len)?;
+                }
+
+                Ok(DnsRecord::TXT {
+                    domain,
+                    data,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut srv = String::new();
+                buffer.read_qname(&mut srv)?;
+
+                Ok(DnsRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host: srv,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::SVCB | QueryType::HTTPS => {
+                let priority = buffer.read_u16()?;
+                let mut target = String::new();
+                buffer.read_qname(&mut target)?;
+
+                let mut params = Vec::new();
+                while buffer.pos() < end {
+                    let key = buffer.read_u16()?;
+                    let len = buffer.read_u16()? as usize;
+                    let value = buffer.get_range(buffer.pos(), len)?.to_vec();
+                    buffer.step(len)?;
+
+                    params.push(SvcParam { key, value });
+                }
+
+                if qtype == QueryType::SVCB {
+                    Ok(DnsRecord::SVCB {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        class,
+                        ttl,
+                    })
+                } else {
+                    Ok(DnsRecord::HTTPS {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        class,
+                        ttl,
+                    })
+                }
+            }
+            QueryType::CAA => {
+                let flags = buffer.read()?;
+                let tag_len = buffer.read()? as usize;
+                let tag = buffer.get_range(buffer.pos(), tag_len)?.to_vec();
+                buffer.step(tag_len)?;
+
+                // The value runs until the end of the record.
+                let value_len = end.saturating_sub(buffer.pos());
+                let value = buffer.get_range(buffer.pos(), value_len)?.to_vec();
+                buffer.step(value_len)?;
+
+                Ok(DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag: String::from_utf8_lossy(&tag).to_string(),
+                    value,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                // The class and ttl fields are reused to carry the EDNS header.
+                let mut options = Vec::new();
+                while buffer.pos() < end {
+                    let code = buffer.read_u16()?;
+                    let len = buffer.read_u16()? as usize;
+                    let data = buffer.get_range(buffer.pos(), len)?.to_vec();
+                    buffer.step(len)?;
+
+                    options.push(EdnsOption { code, data });
+                }
+
+                Ok(DnsRecord::OPT {
+                    packet_len: raw_class,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: ((ttl >> 16) & 0xFF) as u8,
+                    dnssec_ok: (ttl & 0x8000) > 0,
+                    options,
+                })
+            }
             QueryType::UNKNOWN(_) => {
+                // Keep the RDATA as is, so it can be handed on untouched.
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
+                    class,
                     ttl,
                 })
             }
-        }
+        };
+
+        let record = record?;
+
+        // Skip anything the record carries past the fields we know about.
+        buffer.seek(end)?;
+
+        Ok(record)
     }
 
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
@@ -132,11 +485,12 @@ impl DnsRecord {
             DnsRecord::A {
                 ref domain,
                 ref addr,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(4)?;
 
@@ -149,11 +503,12 @@ impl DnsRecord {
             DnsRecord::NS {
                 ref domain,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -167,11 +522,12 @@ impl DnsRecord {
             DnsRecord::CNAME {
                 ref domain,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::CNAME.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -182,15 +538,36 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::DNAME {
+                ref domain,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNAME.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // The target must not be compressed (RFC 6672 2.5).
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::MX.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -205,11 +582,12 @@ impl DnsRecord {
             DnsRecord::AAAA {
                 ref domain,
                 ref addr,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::AAAA.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(16)?;
 
@@ -217,11 +595,1142 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::TXT {
+                ref domain,
+                ref data,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for string in data {
+                    if string.len() > 0xFF {
+                        return Err("Character string exceeds 255 bytes of length".into());
+                    }
+                    buffer.write_u8(string.len() as u8)?;
+                    buffer.write_bytes(string)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SVCB {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                class,
+                ttl,
+            }
+            | DnsRecord::HTTPS {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                class,
+                ttl,
+            } => {
+                let qtype = match self {
+                    DnsRecord::SVCB { .. } => QueryType::SVCB,
+                    _ => QueryType::HTTPS,
+                };
+
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_qname_uncompressed(target)?;
+                for param in params {
+                    buffer.write_u16(param.key)?;
+                    buffer.write_u16(param.value.len() as u16)?;
+                    buffer.write_bytes(&param.value)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CAA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                if tag.len() > 0xFF {
+                    return Err("CAA tag is longer than 255 bytes".into());
+                }
+
+                buffer.write_u8(flags)?;
+                buffer.write_u8(tag.len() as u8)?;
+                buffer.write_bytes(tag.as_bytes())?;
+                buffer.write_bytes(value)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::OPT {
+                packet_len,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                ref options,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(
+                    ((extended_rcode as u32) << 24)
+                        | ((version as u32) << 16)
+                        | ((dnssec_ok as u32) << 15),
+                )?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for option in options {
+                    buffer.write_u16(option.code)?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    buffer.write_bytes(&option.data)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
+
+    /// Build a record from its zone file presentation, which is how `apnd`
+    /// rules spell out the answer they give.
+    pub fn from_text(
+        domain: &str,
+        qtype: QueryType,
+        class: QueryClass,
+        ttl: u32,
+        text: &str,
+    ) -> Result<DnsRecord> {
+        // RDATA of any type can be given in the generic `\# len hex` form
+        // (RFC 3597 5). Put it on the wire and read it back, so types we
+        // know come out as their own variant.
+        if let Some(generic) = text.trim().strip_prefix("\\#") {
+            let data = parse_generic_data(generic)?;
+
+            let mut buffer = BytePacketBuffer::new();
+            buffer.write_qname(domain)?;
+            buffer.write_u16(qtype.to_num())?;
+            buffer.write_u16(class.to_num())?;
+            buffer.write_u32(ttl)?;
+            buffer.write_u16(data.len() as u16)?;
+            buffer.write_bytes(&data)?;
+
+            let mut buffer = BytePacketBuffer::from_bytes(buffer.as_bytes())?;
+            return DnsRecord::read(&mut buffer);
+        }
+
+        let domain = domain.to_string();
+        let fields: Vec<&str> = text.split_whitespace().collect();
+
+        match qtype {
+            QueryType::A => Ok(DnsRecord::A {
+                domain,
+                addr: field(&fields, 0)?.parse()?,
+                class,
+                ttl,
+            }),
+            QueryType::AAAA => Ok(DnsRecord::AAAA {
+                domain,
+                addr: field(&fields, 0)?.parse()?,
+                class,
+                ttl,
+            }),
+            QueryType::NS => Ok(DnsRecord::NS {
+                domain,
+                host: name(field(&fields, 0)?),
+                class,
+                ttl,
+            }),
+            QueryType::CNAME => Ok(DnsRecord::CNAME {
+                domain,
+                host: name(field(&fields, 0)?),
+                class,
+                ttl,
+            }),
+            QueryType::PTR => Ok(DnsRecord::PTR {
+                domain,
+                host: name(field(&fields, 0)?),
+                class,
+                ttl,
+            }),
+            QueryType::DNAME => Ok(DnsRecord::DNAME {
+                domain,
+                host: name(field(&fields, 0)?),
+                class,
+                ttl,
+            }),
+            QueryType::MX => Ok(DnsRecord::MX {
+                domain,
+                priority: field(&fields, 0)?.parse()?,
+                host: name(field(&fields, 1)?),
+                class,
+                ttl,
+            }),
+            QueryType::SOA => Ok(DnsRecord::SOA {
+                domain,
+                mname: name(field(&fields, 0)?),
+                rname: name(field(&fields, 1)?),
+                serial: field(&fields, 2)?.parse()?,
+                refresh: field(&fields, 3)?.parse()?,
+                retry: field(&fields, 4)?.parse()?,
+                expire: field(&fields, 5)?.parse()?,
+                minimum: field(&fields, 6)?.parse()?,
+                class,
+                ttl,
+            }),
+            QueryType::TXT => {
+                // Long text is split over as many character strings as needed.
+                let data = character_strings(text)?
+                    .into_iter()
+                    .flat_map(|string| {
+                        if string.is_empty() {
+                            vec![string]
+                        } else {
+                            string.chunks(0xFF).map(|c| c.to_vec()).collect()
+                        }
+                    })
+                    .collect();
+
+                Ok(DnsRecord::TXT {
+                    domain,
+                    data,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::SRV => Ok(DnsRecord::SRV {
+                domain,
+                priority: field(&fields, 0)?.parse()?,
+                weight: field(&fields, 1)?.parse()?,
+                port: field(&fields, 2)?.parse()?,
+                host: name(field(&fields, 3)?),
+                class,
+                ttl,
+            }),
+            QueryType::SVCB | QueryType::HTTPS => {
+                let priority = field(&fields, 0)?.parse()?;
+                let target = name(field(&fields, 1)?);
+                let params = svc_params(after_fields(text, 2))?;
+
+                if qtype == QueryType::SVCB {
+                    Ok(DnsRecord::SVCB {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        class,
+                        ttl,
+                    })
+                } else {
+                    Ok(DnsRecord::HTTPS {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        class,
+                        ttl,
+                    })
+                }
+            }
+            QueryType::CAA => {
+                let flags = field(&fields, 0)?.parse()?;
+                let tag = field(&fields, 1)?.to_string();
+                let value = character_strings(after_fields(text, 2))?.concat();
+
+                Ok(DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value,
+                    class,
+                    ttl,
+                })
+            }
+            _ => Err(format!("{} records can only be written as \\# data", qtype).into()),
+        }
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    /// Zone file presentation, with RDATA of unknown types in the generic
+    /// form from RFC 3597.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsRecord::A {
+                domain,
+                addr,
+                class,
+                ttl,
+            } => write!(f, "{} {} {} A {}", domain, ttl, class, addr),
+            DnsRecord::AAAA {
+                domain,
+                addr,
+                class,
+                ttl,
+            } => {
+                write!(f, "{} {} {} AAAA {}", domain, ttl, class, addr)
+            }
+            DnsRecord::NS {
+                domain,
+                host,
+                class,
+                ttl,
+            } => write!(f, "{} {} {} NS {}", domain, ttl, class, host),
+            DnsRecord::CNAME {
+                domain,
+                host,
+                class,
+                ttl,
+            } => {
+                write!(f, "{} {} {} CNAME {}", domain, ttl, class, host)
+            }
+            DnsRecord::PTR {
+                domain,
+                host,
+                class,
+                ttl,
+            } => {
+                write!(f, "{} {} {} PTR {}", domain, ttl, class, host)
+            }
+            DnsRecord::DNAME {
+                domain,
+                host,
+                class,
+                ttl,
+            } => {
+                write!(f, "{} {} {} DNAME {}", domain, ttl, class, host)
+            }
+            DnsRecord::MX {
+                domain,
+                priority,
+                host,
+                class,
+                ttl,
+            } => write!(f, "{} {} {} MX {} {}", domain, ttl, class, priority, host),
+            DnsRecord::SOA {
+                domain,
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                class,
+                ttl,
+            } => write!(
+                f,
+                "{} {} {} SOA {} {} {} {} {} {} {}",
+                domain, ttl, class, mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            DnsRecord::TXT {
+                domain,
+                data,
+                class,
+                ttl,
+            } => {
+                write!(f, "{} {} {} TXT", domain, ttl, class)?;
+                for string in data {
+                    write!(f, " \"{}\"", escape(string))?;
+                }
+                Ok(())
+            }
+            DnsRecord::SRV {
+                domain,
+                priority,
+                weight,
+                port,
+                host,
+                class,
+                ttl,
+            } => write!(
+                f,
+                "{} {} {} SRV {} {} {} {}",
+                domain, ttl, class, priority, weight, port, host
+            ),
+            DnsRecord::SVCB {
+                domain,
+                priority,
+                target,
+                params,
+                class,
+                ttl,
+            }
+            | DnsRecord::HTTPS {
+                domain,
+                priority,
+                target,
+                params,
+                class,
+                ttl,
+            } => {
+                let qtype = match self {
+                    DnsRecord::SVCB { .. } => QueryType::SVCB,
+                    _ => QueryType::HTTPS,
+                };
+                write!(
+                    f,
+                    "{} {} {} {} {} {}",
+                    domain,
+                    ttl,
+                    class,
+                    qtype,
+                    priority,
+                    // The root, which stands for the owner itself.
+                    if target.is_empty() { "." } else { target }
+                )?;
+                for param in params {
+                    write!(f, " key{}=\"{}\"", param.key, escape(&param.value))?;
+                }
+                Ok(())
+            }
+            DnsRecord::CAA {
+                domain,
+                flags,
+                tag,
+                value,
+                class,
+                ttl,
+            } => write!(
+                f,
+                "{} {} {} CAA {} {} \"{}\"",
+                domain,
+                ttl,
+                class,
+                flags,
+                tag,
+                escape(value)
+            ),
+            DnsRecord::OPT {
+                packet_len,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                options,
+            } => write!(
+                f,
+                "; EDNS version {} udp {} rcode {} do {} options {}",
+                version,
+                packet_len,
+                extended_rcode,
+                dnssec_ok,
+                options.len()
+            ),
+            DnsRecord::UNKNOWN {
+                domain,
+                qtype,
+                data,
+                class,
+                ttl,
+            } => {
+                write!(
+                    f,
+                    "{} {} {} {} \\# {}",
+                    domain,
+                    ttl,
+                    class,
+                    QueryType::from_num(*qtype),
+                    data.len()
+                )?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                }
+                for b in data {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Decode the `len hex` part of generic RDATA, the hex may be split up by
+/// whitespace.
+fn parse_generic_data(raw: &str) -> Result<Vec<u8>> {
+    let mut fields = raw.split_whitespace();
+    let len = match fields.next() {
+        Some(len) => len.parse::<usize>()?,
+        None => return Err("Missing length of generic data".into()),
+    };
+
+    let hex: String = fields.collect();
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err("Generic data must be an even number of hex digits".into());
+    }
+
+    let mut data = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        data.push(u8::from_str_radix(&hex[i..i + 2], 16)?);
+    }
+
+    if data.len() != len {
+        return Err(format!("Generic data is {} bytes, expected {}", data.len(), len).into());
+    }
+
+    Ok(data)
+}
+
+/// Character strings with quotes, backslashes and non printable bytes escaped.
+fn escape(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len());
+    for b in data {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(*b as char);
+            }
+            0x20..=0x7E => out.push(*b as char),
+            _ => out.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    out
+}
+
+fn field<'a>(fields: &[&'a str], index: usize) -> Result<&'a str> {
+    match fields.get(index) {
+        Some(field) => Ok(field),
+        None => Err(format!("Missing field {} of record data", index + 1).into()),
+    }
+}
+
+/// Names in zone files may be written fully qualified, with a trailing dot.
+fn name(raw: &str) -> String {
+    raw.trim_end_matches('.').to_lowercase()
+}
+
+/// What is left of `text` after its first `count` fields.
+fn after_fields(text: &str, count: usize) -> &str {
+    let mut rest = text.trim_start();
+    for _ in 0..count {
+        rest = rest
+            .trim_start_matches(|c: char| !c.is_whitespace())
+            .trim_start();
+    }
+    rest
+}
+
+/// SvcParams in presentation form (RFC 9460 2.1). Values are given as
+/// `alpn=h2,h3`, `port=443`, `ipv4hint=` and `ipv6hint=` lists, or as
+/// `keyNNNNN="..."` with the wire format value, which is how they are shown.
+fn svc_params(raw: &str) -> Result<Vec<SvcParam>> {
+    let raw = raw.trim().as_bytes();
+    let mut params: Vec<SvcParam> = Vec::new();
+    let mut pos = 0;
+    while pos < raw.len() {
+        if raw[pos].is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        }
+
+        let start = pos;
+        while pos < raw.len() && !raw[pos].is_ascii_whitespace() && raw[pos] != b'=' {
+            pos += 1;
+        }
+        let key = std::str::from_utf8(&raw[start..pos])?;
+
+        // The value may be quoted, then it can hold whitespace.
+        let mut value = None;
+        if raw.get(pos) == Some(&b'=') {
+            pos += 1;
+            let quoted = raw.get(pos) == Some(&b'"');
+            if quoted {
+                pos += 1;
+            }
+            let start = pos;
+            while pos < raw.len() && raw[pos] != b'"' && (quoted || !raw[pos].is_ascii_whitespace())
+            {
+                pos += if raw[pos] == b'\\' { 2 } else { 1 };
+            }
+            if quoted && pos >= raw.len() {
+                return Err(format!("Unterminated value of {}", key).into());
+            }
+            value = Some(unescape(&raw[start..pos.min(raw.len())])?);
+            if quoted {
+                pos += 1;
+            }
+        }
+
+        let number = svc_key(key)?;
+        let value = match key.starts_with("key") {
+            true => value.unwrap_or_default(),
+            false => svc_value(key, number, value)?,
+        };
+        params.push(SvcParam { key: number, value });
+    }
+
+    // Keys go in increasing order on the wire, each only once.
+    params.sort_by_key(|param| param.key);
+    if params.windows(2).any(|pair| pair[0].key == pair[1].key) {
+        return Err("SvcParam keys must not repeat".into());
+    }
+
+    Ok(params)
+}
+
+/// The number of a SvcParam key, given by name or as `keyNNNNN`.
+fn svc_key(key: &str) -> Result<u16> {
+    match key {
+        "mandatory" => Ok(0),
+        "alpn" => Ok(1),
+        "no-default-alpn" => Ok(2),
+        "port" => Ok(3),
+        "ipv4hint" => Ok(4),
+        "ipv6hint" => Ok(6),
+        _ => match key.strip_prefix("key").map(str::parse::<u16>) {
+            Some(Ok(number)) => Ok(number),
+            _ => Err(format!("Unknown SvcParam key {}", key).into()),
+        },
+    }
+}
+
+/// The wire format of a value given in presentation form for a key that
+/// was named.
+fn svc_value(key: &str, number: u16, value: Option<Vec<u8>>) -> Result<Vec<u8>> {
+    if number == 2 {
+        return match value {
+            Some(_) => Err("no-default-alpn takes no value".into()),
+            None => Ok(Vec::new()),
+        };
+    }
+
+    let text = match value {
+        Some(value) if !value.is_empty() => String::from_utf8(value)?,
+        _ => return Err(format!("Missing value of {}", key).into()),
+    };
+    let mut value = Vec::new();
+    for item in text.split(',') {
+        match number {
+            0 => value.extend_from_slice(&svc_key(item)?.to_be_bytes()),
+            1 => {
+                if item.is_empty() || item.len() > 0xFF {
+                    return Err(format!("Invalid alpn id {}", item).into());
+                }
+                value.push(item.len() as u8);
+                value.extend_from_slice(item.as_bytes());
+            }
+            3 => value.extend_from_slice(&item.parse::<u16>()?.to_be_bytes()),
+            4 => value.extend_from_slice(&item.parse::<Ipv4Addr>()?.octets()),
+            _ => value.extend_from_slice(&item.parse::<Ipv6Addr>()?.octets()),
+        }
+    }
+    if number == 3 && value.len() != 2 {
+        return Err("port takes a single number".into());
+    }
+
+    Ok(value)
+}
+
+/// Quoted text is read as a list of character strings, anything else as a
+/// single one. Both may use the escapes written by `escape`.
+fn character_strings(raw: &str) -> Result<Vec<Vec<u8>>> {
+    let raw = raw.trim().as_bytes();
+    if !raw.starts_with(b"\"") {
+        if raw.is_empty() {
+            return Ok(Vec::new());
+        }
+        return Ok(vec![unescape(raw)?]);
+    }
+
+    let mut strings = Vec::new();
+    let mut pos = 0;
+    while pos < raw.len() {
+        if raw[pos].is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        }
+        if raw[pos] != b'"' {
+            return Err("Character strings must all be quoted".into());
+        }
+
+        // Find the closing quote, stepping over escaped characters.
+        let start = pos + 1;
+        let mut end = start;
+        while end < raw.len() && raw[end] != b'"' {
+            end += if raw[end] == b'\\' { 2 } else { 1 };
+        }
+        if end >= raw.len() {
+            return Err("Unterminated character string".into());
+        }
+
+        strings.push(unescape(&raw[start..end])?);
+        pos = end + 1;
+    }
+
+    Ok(strings)
+}
+
+/// Undo `escape`, a backslash followed by three digits is a byte value and
+/// followed by anything else is that character itself.
+fn unescape(raw: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut pos = 0;
+    while pos < raw.len() {
+        if raw[pos] != b'\\' {
+            out.push(raw[pos]);
+            pos += 1;
+            continue;
+        }
+
+        let digits = raw.get(pos + 1..pos + 4).unwrap_or(&[]);
+        if digits.len() == 3 && digits.iter().all(u8::is_ascii_digit) {
+            let value: u16 = std::str::from_utf8(digits)?.parse()?;
+            out.push(u8::try_from(value).map_err(|_| "Escaped byte is above 255")?);
+            pos += 4;
+        } else if let Some(b) = raw.get(pos + 1) {
+            out.push(*b);
+            pos += 2;
+        } else {
+            return Err("Character string ends in a backslash".into());
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(record: &DnsRecord) -> DnsRecord {
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_bytes()).unwrap();
+        DnsRecord::read(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = [
+            DnsRecord::from_text(
+                "example.com",
+                QueryType::TXT,
+                QueryClass::IN,
+                60,
+                "\"v=spf1 -all\"",
+            )
+            .unwrap(),
+            DnsRecord::from_text(
+                "example.com",
+                QueryType::SOA,
+                QueryClass::IN,
+                60,
+                "ns1.example.com. hostmaster.example.com. 2023101701 7200 3600 1209600 300",
+            )
+            .unwrap(),
+            DnsRecord::from_text(
+                "1.0.0.10.in-addr.arpa",
+                QueryType::PTR,
+                QueryClass::IN,
+                60,
+                "host.example.com",
+            )
+            .unwrap(),
+            DnsRecord::from_text(
+                "old.example.com",
+                QueryType::DNAME,
+                QueryClass::IN,
+                60,
+                "new.example.org.",
+            )
+            .unwrap(),
+            DnsRecord::from_text(
+                "_sip._tcp.example.com",
+                QueryType::SRV,
+                QueryClass::IN,
+                60,
+                "10 5 5060 sip.example.com",
+            )
+            .unwrap(),
+            DnsRecord::from_text(
+                "example.com",
+                QueryType::CAA,
+                QueryClass::IN,
+                60,
+                "0 issue \"letsencrypt.org\"",
+            )
+            .unwrap(),
+            DnsRecord::HTTPS {
+                domain: "example.com".to_string(),
+                priority: 1,
+                target: "".to_string(),
+                params: vec![SvcParam {
+                    key: 1,
+                    value: b"\x02h2".to_vec(),
+                }],
+                class: QueryClass::IN,
+                ttl: 60,
+            },
+        ];
+
+        for record in &records {
+            assert_eq!(&round_trip(record), record);
+        }
+    }
+
+    #[test]
+    fn long_txt_is_split() {
+        let text = "a".repeat(300);
+        let record =
+            DnsRecord::from_text("example.com", QueryType::TXT, QueryClass::IN, 60, &text).unwrap();
+
+        match round_trip(&record) {
+            DnsRecord::TXT { data, .. } => {
+                assert_eq!(data.len(), 2);
+                assert_eq!(data.concat(), text.as_bytes());
+            }
+            other => panic!("Expected TXT, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn txt_text_is_split_into_character_strings() {
+        let text = r#""a" "b c"  "say \"hi\" \\o/" "\009""#;
+        let record =
+            DnsRecord::from_text("example.com", QueryType::TXT, QueryClass::IN, 60, text).unwrap();
+
+        match &record {
+            DnsRecord::TXT { data, .. } => assert_eq!(
+                data,
+                &vec![
+                    b"a".to_vec(),
+                    b"b c".to_vec(),
+                    br#"say "hi" \o/"#.to_vec(),
+                    vec![9],
+                ]
+            ),
+            other => panic!("Expected TXT, got {:?}", other),
+        }
+
+        // The presentation form reads back as the same record.
+        let shown = record.to_string();
+        let rdata = shown.split_once(" TXT ").unwrap().1;
+        assert_eq!(
+            DnsRecord::from_text("example.com", QueryType::TXT, QueryClass::IN, 60, rdata).unwrap(),
+            record
+        );
+
+        for bad in [r#""a" b"#, r#""a"#, r#""\256""#] {
+            assert!(
+                DnsRecord::from_text("example.com", QueryType::TXT, QueryClass::IN, 60, bad)
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn long_caa_tags_are_refused() {
+        let record = DnsRecord::CAA {
+            domain: "example.com".to_string(),
+            flags: 0,
+            tag: "t".repeat(256),
+            value: b"ca.example.net".to_vec(),
+            class: QueryClass::IN,
+            ttl: 60,
+        };
+
+        assert!(record.write(&mut BytePacketBuffer::new()).is_err());
+    }
+
+    #[test]
+    fn reads_continue_after_the_record_data() {
+        // An A record with two bytes more than the address, then a marker.
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("example.com").unwrap();
+        buffer.write_u16(QueryType::A.to_num()).unwrap();
+        buffer.write_u16(QueryClass::IN.to_num()).unwrap();
+        buffer.write_u32(60).unwrap();
+        buffer.write_u16(6).unwrap();
+        buffer.write_bytes(&[10, 0, 0, 1, 0xFF, 0xFF]).unwrap();
+        buffer.write_u16(0x1234).unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(buffer.as_bytes()).unwrap();
+        let record = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(record.to_string(), "example.com 60 IN A 10.0.0.1");
+        assert_eq!(buffer.read_u16().unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn svcb_targets_are_shown_like_other_names() {
+        let record = DnsRecord::from_text(
+            "example.com",
+            QueryType::HTTPS,
+            QueryClass::IN,
+            60,
+            "1 svc.example.net.",
+        )
+        .unwrap();
+
+        assert_eq!(
+            record.to_string(),
+            "example.com 60 IN HTTPS 1 svc.example.net"
+        );
+    }
+
+    #[test]
+    fn svc_params_are_read_from_text() {
+        let record = DnsRecord::from_text(
+            "example.com",
+            QueryType::HTTPS,
+            QueryClass::IN,
+            60,
+            "1 . port=8443 alpn=h2,h3 ipv6hint=2001:db8::1 key65000=\"a b\" \
+             ipv4hint=192.0.2.1,192.0.2.2 mandatory=alpn,port",
+        )
+        .unwrap();
+
+        let mut ipv6hint = vec![0x20, 0x01, 0x0d, 0xb8];
+        ipv6hint.extend_from_slice(&[0; 11]);
+        ipv6hint.push(1);
+        let param = |key, value: &[u8]| SvcParam {
+            key,
+            value: value.to_vec(),
+        };
+        match &record {
+            DnsRecord::HTTPS { target, params, .. } => {
+                assert_eq!(target, "");
+                assert_eq!(
+                    params,
+                    &vec![
+                        param(0, &[0, 1, 0, 3]),
+                        param(1, b"\x02h2\x02h3"),
+                        param(3, &8443u16.to_be_bytes()),
+                        param(4, &[192, 0, 2, 1, 192, 0, 2, 2]),
+                        param(6, &ipv6hint),
+                        param(65000, b"a b"),
+                    ]
+                );
+            }
+            other => panic!("Expected HTTPS, got {:?}", other),
+        }
+
+        // On the wire and back, and shown and read back.
+        assert_eq!(round_trip(&record), record);
+        let shown = record.to_string();
+        let rdata = shown.split_once(" HTTPS ").unwrap().1;
+        assert_eq!(
+            DnsRecord::from_text("example.com", QueryType::HTTPS, QueryClass::IN, 60, rdata)
+                .unwrap(),
+            record
+        );
+
+        for bad in [
+            "1 . color=red",
+            "1 . port=443 port=444",
+            "1 . port=https",
+            "1 . alpn",
+            "1 . ipv4hint=192.0.2.300",
+            "1 . key7=\"open",
+        ] {
+            assert!(
+                DnsRecord::from_text("example.com", QueryType::SVCB, QueryClass::IN, 60, bad)
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_records_keep_their_data() {
+        let record = DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65280,
+            data: vec![0xDE, 0xAD, 0xBE, 0xEF],
+            class: QueryClass::IN,
+            ttl: 60,
+        };
+
+        assert_eq!(round_trip(&record), record);
+        assert_eq!(
+            record.to_string(),
+            "example.com 60 IN TYPE65280 \\# 4 deadbeef"
+        );
+    }
+
+    #[test]
+    fn generic_text_data() {
+        let qtype = QueryType::from_name("TYPE65280").unwrap();
+        let record =
+            DnsRecord::from_text("example.com", qtype, QueryClass::IN, 60, "\\# 4 dead beef")
+                .unwrap();
+        assert_eq!(
+            record,
+            DnsRecord::UNKNOWN {
+                domain: "example.com".to_string(),
+                qtype: 65280,
+                data: vec![0xDE, 0xAD, 0xBE, 0xEF],
+                class: QueryClass::IN,
+                ttl: 60,
+            }
+        );
+
+        // Known types given as generic data come out as their own variant.
+        let qtype = QueryType::from_name("TYPE1").unwrap();
+        let record =
+            DnsRecord::from_text("example.com", qtype, QueryClass::IN, 60, "\\# 4 0a000001")
+                .unwrap();
+        assert_eq!(
+            record,
+            DnsRecord::from_text("example.com", QueryType::A, QueryClass::IN, 60, "10.0.0.1")
+                .unwrap()
+        );
+
+        assert!(
+            DnsRecord::from_text("example.com", qtype, QueryClass::IN, 60, "\\# 5 0a000001")
+                .is_err()
+        );
+        assert!(
+            DnsRecord::from_text("example.com", qtype, QueryClass::IN, 60, "\\# 4 0a00001")
+                .is_err()
+        );
+        assert!(DnsRecord::from_text(
+            "example.com",
+            QueryType::UNKNOWN(99),
+            QueryClass::IN,
+            60,
+            "1"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn srv_target_is_not_compressed() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("sip.example.com").unwrap();
+        let start = buffer.pos();
+
+        let record = DnsRecord::from_text(
+            "sip.example.com",
+            QueryType::SRV,
+            QueryClass::IN,
+            60,
+            "0 0 5060 sip.example.com",
+        )
+        .unwrap();
+        record.write(&mut buffer).unwrap();
+
+        // Pointer owner, fixed fields, then the target in full.
+        assert_eq!(buffer.pos() - start, 2 + 10 + 6 + 17);
+    }
 }
@@ -1,22 +1,43 @@
+use std::fmt;
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,     // 1
     NS,    // 2
     CNAME, // 5
+    SOA,   // 6
+    PTR,   // 12
     MX,    // 15
+    TXT,   // 16
     AAAA,  // 28
+    SRV,   // 33
+    DNAME, // 39
+    OPT,   // 41
+    SVCB,  // 64
+    HTTPS, // 65
+    CAA,   // 257
 }
 
 impl QueryType {
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             QueryType::UNKNOWN(x) => x,
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::DNAME => 39,
+            QueryType::OPT => 41,
+            QueryType::SVCB => 64,
+            QueryType::HTTPS => 65,
+            QueryType::CAA => 257,
         }
     }
 
@@ -25,9 +46,51 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            39 => QueryType::DNAME,
+            41 => QueryType::OPT,
+            64 => QueryType::SVCB,
+            65 => QueryType::HTTPS,
+            257 => QueryType::CAA,
             _ => QueryType::UNKNOWN(num),
         }
     }
+
+    pub fn from_name(name: &str) -> Option<QueryType> {
+        match name.to_uppercase().as_str() {
+            "A" => Some(QueryType::A),
+            "NS" => Some(QueryType::NS),
+            "CNAME" => Some(QueryType::CNAME),
+            "SOA" => Some(QueryType::SOA),
+            "PTR" => Some(QueryType::PTR),
+            "MX" => Some(QueryType::MX),
+            "TXT" => Some(QueryType::TXT),
+            "AAAA" => Some(QueryType::AAAA),
+            "SRV" => Some(QueryType::SRV),
+            "DNAME" => Some(QueryType::DNAME),
+            "SVCB" => Some(QueryType::SVCB),
+            "HTTPS" => Some(QueryType::HTTPS),
+            "CAA" => Some(QueryType::CAA),
+            "OPT" => Some(QueryType::OPT),
+            // Types without a mnemonic are written as TYPE### (RFC 3597 5).
+            name => match name.strip_prefix("TYPE").map(|num| num.parse::<u16>()) {
+                Some(Ok(num)) => Some(QueryType::from_num(num)),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            known => write!(f, "{:?}", known),
+        }
+    }
 }
@@ -0,0 +1,368 @@
+use std::collections::{BTreeMap, HashMap};
+
+use regex::{RegexSet, RegexSetBuilder};
+
+use super::{Rule, M_ANY, M_END, M_EQUAL, M_GLOB, M_REGEX, M_START, M_TREE};
+use crate::protocol::Result;
+
+/// Node of the name trie, reached by the labels of a name from the root
+/// down, so `www.example.com` is at `com` -> `example` -> `www`.
+#[derive(Default)]
+struct NameNode {
+    children: HashMap<Box<str>, NameNode>,
+    /// `example.com`, this very name.
+    exact: Option<usize>,
+    /// `*.example.com`, every name below this one.
+    below: Option<usize>,
+    /// `**.example.com`, this name and every name below it.
+    tree: Option<usize>,
+}
+
+/// Node of the prefix trie, reached by the labels of a name from the start.
+#[derive(Default)]
+struct PrefixNode {
+    children: HashMap<Box<str>, PrefixNode>,
+    /// `example.*`, names going on after these labels.
+    rule: Option<usize>,
+}
+
+/// Keep whichever rule came first, so earlier files and lines win ties.
+fn first(slot: &mut Option<usize>, index: usize) {
+    if slot.is_none() {
+        *slot = Some(index);
+    }
+}
+
+fn earliest(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
+
+/// Rules indexed by what they match, so looking a name up costs a walk
+/// over its labels instead of a pass over every rule.
+///
+/// When several rules match a name the most specific one applies: a rule
+/// for the name itself, then the rule for the longest matching parent
+/// (`*.example.com` or `**.example.com`), then the longest matching prefix
+/// (`example.*`), then the first regex or glob that matches, then `*`.
+/// Between equally specific rules the one loaded first applies.
+///
+/// Exceptions (`!` rules and `allow`) are kept apart and checked after
+/// that: if any exception for the action of the rule found matches the
+/// name, the exception applies instead, however specific the rule was.
+#[derive(Default)]
+pub struct RuleIndex {
+    rules: Vec<Rule>,
+    matcher: Matcher,
+    /// Exceptions by the action they make an exception to.
+    exceptions: BTreeMap<u8, Matcher>,
+}
+
+impl RuleIndex {
+    pub fn new(rules: Vec<Rule>) -> Result<RuleIndex> {
+        let mut index = RuleIndex::default();
+        for (i, rule) in rules.iter().enumerate() {
+            match rule.reverse {
+                false => index.matcher.insert(i, rule),
+                true => index
+                    .exceptions
+                    .entry(rule.action)
+                    .or_default()
+                    .insert(i, rule),
+            }
+        }
+        index.matcher.compile(&rules)?;
+        for exceptions in index.exceptions.values_mut() {
+            exceptions.compile(&rules)?;
+        }
+        index.rules = rules;
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// The rule that applies to `name`, if any. Case and a trailing dot
+    /// don't matter. An exception found here means no rule applies.
+    pub fn find(&self, name: &str) -> Option<&Rule> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let rule = &self.rules[self.matcher.find_index(&name)?];
+        let exception = self
+            .exceptions
+            .get(&rule.action)
+            .and_then(|exceptions| exceptions.find_index(&name));
+
+        match exception {
+            Some(i) => Some(&self.rules[i]),
+            None => Some(rule),
+        }
+    }
+}
+
+/// One set of rules, indexed for finding the most specific match.
+#[derive(Default)]
+struct Matcher {
+    names: NameNode,
+    prefixes: PrefixNode,
+    /// Regex and glob rules, by their place in `pattern_set`.
+    patterns: Vec<usize>,
+    /// All regex and glob rules compiled together, so a name is checked
+    /// against them in one pass.
+    pattern_set: RegexSet,
+    /// `*`, every name.
+    any: Option<usize>,
+}
+
+impl Matcher {
+    fn insert(&mut self, i: usize, rule: &Rule) {
+        match rule.mode {
+            M_EQUAL => first(&mut self.name_node(&rule.key).exact, i),
+            M_END => first(&mut self.name_node(&rule.key).below, i),
+            M_TREE => first(&mut self.name_node(&rule.key).tree, i),
+            M_START => {
+                let mut node = &mut self.prefixes;
+                for label in rule.key.split('.') {
+                    node = node.children.entry(label.into()).or_default();
+                }
+                first(&mut node.rule, i);
+            }
+            M_ANY => first(&mut self.any, i),
+            M_REGEX | M_GLOB => self.patterns.push(i),
+            _ => {}
+        }
+    }
+
+    fn name_node(&mut self, name: &str) -> &mut NameNode {
+        let mut node = &mut self.names;
+        for label in name.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        node
+    }
+
+    /// Compile the regex and glob rules inserted, `rules` being all rules.
+    fn compile(&mut self, rules: &[Rule]) -> Result<()> {
+        self.pattern_set = RegexSetBuilder::new(self.patterns.iter().map(|&i| &rules[i].key))
+            .case_insensitive(true)
+            .build()?;
+        Ok(())
+    }
+
+    fn find_index(&self, name: &str) -> Option<usize> {
+        if name.is_empty() {
+            return self.any;
+        }
+
+        // Walk down the labels, remembering the deepest parent with a rule
+        // for the names below it.
+        let mut node = &self.names;
+        let mut parent = None;
+        let mut labels = name.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            node = match node.children.get(label) {
+                Some(child) => child,
+                None => break,
+            };
+            match labels.peek() {
+                Some(_) => parent = earliest(node.below, node.tree).or(parent),
+                None if node.exact.is_some() => return node.exact,
+                None => parent = node.tree.or(parent),
+            }
+        }
+        if parent.is_some() {
+            return parent;
+        }
+
+        let mut node = &self.prefixes;
+        let mut prefix = None;
+        let mut labels = name.split('.').peekable();
+        while let Some(label) = labels.next() {
+            node = match node.children.get(label) {
+                Some(child) => child,
+                None => break,
+            };
+            if labels.peek().is_some() {
+                prefix = node.rule.or(prefix);
+            }
+        }
+
+        if prefix.is_some() {
+            return prefix;
+        }
+
+        // Patterns are in load order, so the first match is the earliest.
+        let pattern = self.pattern_set.matches(name).into_iter().next();
+        pattern.map(|i| self.patterns[i]).or(self.any)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::rules::{parse_rule, A_APPEND, A_DENY};
+
+    fn index(lines: &[&str]) -> RuleIndex {
+        RuleIndex::new(lines.iter().map(|line| parse_rule(line).unwrap()).collect()).unwrap()
+    }
+
+    fn value<'a>(index: &'a RuleIndex, name: &str) -> Option<&'a str> {
+        index.find(name).and_then(|rule| rule.value.as_deref())
+    }
+
+    #[test]
+    fn most_specific_rule_applies() {
+        let index = index(&[
+            "apnd * 10.0.0.0",
+            "apnd ads.* 10.0.0.1",
+            "apnd *.example.com 10.0.0.2",
+            "apnd *.www.example.com 10.0.0.3",
+            "apnd www.example.com 10.0.0.4",
+            "apnd www.example.com 10.0.0.5",
+        ]);
+
+        assert_eq!(value(&index, "www.example.com"), Some("10.0.0.4"));
+        assert_eq!(value(&index, "a.www.example.com"), Some("10.0.0.3"));
+        assert_eq!(value(&index, "mail.example.com"), Some("10.0.0.2"));
+        assert_eq!(value(&index, "example.com"), Some("10.0.0.0"));
+        assert_eq!(value(&index, "ads.example.com"), Some("10.0.0.2"));
+        assert_eq!(value(&index, "ads.example.org"), Some("10.0.0.1"));
+        assert_eq!(value(&index, "example.org"), Some("10.0.0.0"));
+    }
+
+    #[test]
+    fn wildcards_stand_for_whole_labels() {
+        let index = index(&[
+            "apnd *.below.com 10.0.0.1",
+            "apnd **.tree.com 10.0.0.2",
+            "apnd start.* 10.0.0.3",
+        ]);
+
+        assert_eq!(value(&index, "a.below.com"), Some("10.0.0.1"));
+        assert_eq!(value(&index, "a.b.below.com"), Some("10.0.0.1"));
+        assert_eq!(value(&index, "below.com"), None);
+        assert_eq!(value(&index, "notbelow.com"), None);
+        assert_eq!(value(&index, "tree.com"), Some("10.0.0.2"));
+        assert_eq!(value(&index, "a.tree.com"), Some("10.0.0.2"));
+        assert_eq!(value(&index, "badtree.com"), None);
+        assert_eq!(value(&index, "start.example.com"), Some("10.0.0.3"));
+        assert_eq!(value(&index, "start"), None);
+        assert_eq!(value(&index, "starter.com"), None);
+        assert_eq!(value(&index, "A.Below.COM."), Some("10.0.0.1"));
+        assert_eq!(value(&index, "Tree.com."), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn exceptions_override_rules_with_their_action() {
+        let index = index(&[
+            "deny **.facebook.com",
+            "deny ads.business.facebook.com",
+            "allow business.facebook.com",
+            "deny !**.cdn.facebook.com",
+            "apnd *.example.com 10.0.0.1",
+            "allow **.example.com",
+            "apnd !www.example.com",
+        ]);
+        let applies = |name: &str| index.find(name).map(|rule| (rule.action, rule.reverse));
+
+        assert_eq!(applies("facebook.com"), Some((A_DENY, false)));
+        assert_eq!(applies("business.facebook.com"), Some((A_DENY, true)));
+        assert_eq!(applies("a.business.facebook.com"), Some((A_DENY, false)));
+        assert_eq!(applies("ads.business.facebook.com"), Some((A_DENY, false)));
+        assert_eq!(applies("img.cdn.facebook.com"), Some((A_DENY, true)));
+        assert_eq!(applies("mail.example.com"), Some((A_APPEND, false)));
+        assert_eq!(applies("www.example.com"), Some((A_APPEND, true)));
+        assert_eq!(applies("example.org"), None);
+    }
+
+    #[test]
+    fn patterns_come_after_names_and_prefixes() {
+        let index = index(&[
+            "apnd * 10.0.0.0",
+            "apnd /^ads[0-9]+\\./ 10.0.0.1",
+            "apnd tracker-*.cdn.* 10.0.0.2",
+            "apnd ads1.example.com 10.0.0.3",
+            "apnd ads*.* 10.0.0.4",
+        ]);
+
+        assert_eq!(value(&index, "ads1.example.com"), Some("10.0.0.3"));
+        assert_eq!(value(&index, "ADS2.example.com."), Some("10.0.0.1"));
+        assert_eq!(value(&index, "adsx.example.com"), Some("10.0.0.4"));
+        assert_eq!(value(&index, "tracker-7.cdn.net"), Some("10.0.0.2"));
+        assert_eq!(value(&index, "tracker.cdn.net"), Some("10.0.0.0"));
+    }
+
+    /// Compares lookups against a linear scan over a large synthetic list.
+    /// Run with `cargo test --release -- --ignored --nocapture lookup_latency`.
+    #[test]
+    #[ignore]
+    fn lookup_latency() {
+        const RULES: usize = 1_000_000;
+        const LOOKUPS: usize = 100_000;
+
+        let lines: Vec<String> = (0..RULES)
+            .map(|i| match i % 4 {
+                0 => format!("deny *.tracker{}.example{}.com", i, i % 1000),
+                1 => format!("deny ads{}.*", i),
+                _ => format!("deny host{}.example{}.net", i, i % 1000),
+            })
+            .collect();
+        let start = Instant::now();
+        let rules: Vec<Rule> = lines.iter().map(|line| parse_rule(line).unwrap()).collect();
+        let index = RuleIndex::new(rules.clone()).unwrap();
+        println!("indexed {} rules in {:?}", RULES, start.elapsed());
+
+        let names: Vec<String> = (0..LOOKUPS)
+            .map(|i| match i % 3 {
+                0 => format!("a.tracker{}.example{}.com", i * 4, (i * 4) % 1000),
+                1 => format!("host{}.example{}.net", i * 4 + 2, (i * 4 + 2) % 1000),
+                _ => format!("miss{}.example.org", i),
+            })
+            .collect();
+
+        let start = Instant::now();
+        let matched = names
+            .iter()
+            .filter(|name| index.find(name).is_some())
+            .count();
+        let per_lookup = start.elapsed() / LOOKUPS as u32;
+        println!(
+            "index: {} of {} matched, {:?} per lookup",
+            matched, LOOKUPS, per_lookup
+        );
+
+        // The previous match_rule: keys kept their dot, so a rule matched
+        // on a plain suffix or prefix, and the first match was cloned.
+        let keys: Vec<(u8, String)> = rules
+            .iter()
+            .map(|rule| match rule.mode {
+                M_END => (rule.mode, format!(".{}", rule.key)),
+                M_START => (rule.mode, format!("{}.", rule.key)),
+                _ => (rule.mode, rule.key.clone()),
+            })
+            .collect();
+        let linear = |name: &str| {
+            keys.iter()
+                .position(|(mode, key)| match *mode {
+                    M_EQUAL => name == key,
+                    M_END => name.ends_with(key.as_str()),
+                    _ => name.starts_with(key.as_str()),
+                })
+                .map(|i| rules[i].clone())
+        };
+        let sample = &names[..100];
+        let start = Instant::now();
+        let linear_matched = sample.iter().filter(|name| linear(name).is_some()).count();
+        let per_lookup = start.elapsed() / sample.len() as u32;
+        println!(
+            "linear scan: {} of {} matched, {:?} per lookup",
+            linear_matched,
+            sample.len(),
+            per_lookup
+        );
+    }
+}
@@ -1,132 +1,460 @@
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf};
 
-use crate::{config::RulesSettings, utils};
+use regex::RegexBuilder;
+
+use crate::{
+    config::{OnError, RulesSettings},
+    protocol::{dns_record::DnsRecord, query_class::QueryClass, query_type::QueryType, Result},
+    utils,
+};
+
+pub mod index;
 
 pub const A_DENY: u8 = 0;
 pub const A_APPEND: u8 = 1;
 
+/// `domain.com`, the name itself.
 pub const M_EQUAL: u8 = 0;
+/// `*.domain.com`, names below it but not the name itself.
 pub const M_END: u8 = 1;
+/// `domain.com.*`, names starting with its labels and going on after them.
 pub const M_START: u8 = 2;
+/// `**.domain.com`, the name itself and every name below it.
+pub const M_TREE: u8 = 3;
+/// `*`, every name.
+pub const M_ANY: u8 = 4;
+/// `/^ads[0-9]+\./`, names the regular expression matches anywhere in.
+pub const M_REGEX: u8 = 5;
+/// `tracker-*.cdn.*`, names the glob matches, kept as a regex in `key`.
+pub const M_GLOB: u8 = 6;
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct Rule {
     pub action: u8,
     pub mode: u8,
+    /// An exception, keeping the rules with the same action from applying.
     pub reverse: bool,
     pub key: String,
+    pub qtype: Option<QueryType>,
     pub value: Option<String>,
 }
 
-pub fn parse_rule(raw: &str) -> Rule {
-    let rule: Vec<&str> = raw.split(" ").collect();
-    let action = match rule[0] {
-        "deny" => A_DENY,
-        "apnd" => A_APPEND,
-        _ => panic!("Invalid action {}", rule[0]),
+/// A line of a rules file that isn't a valid rule.
+#[derive(Debug)]
+pub struct RuleParseError {
+    pub file: PathBuf,
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for RuleParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file.display(), self.line, self.reason)
+    }
+}
+
+impl std::error::Error for RuleParseError {}
+
+/// Parse one rule, or say what is wrong with it.
+pub fn parse_rule(raw: &str) -> std::result::Result<Rule, String> {
+    let raw = raw.trim();
+    let rule: Vec<&str> = raw.split_whitespace().collect();
+    // `!pattern` makes a rule an exception to the rules with its action,
+    // and `allow pattern` is short for `deny !pattern`.
+    let name = rule.first().copied().unwrap_or_default();
+    let (action, allow) = match name {
+        "deny" => (A_DENY, false),
+        "apnd" => (A_APPEND, false),
+        "allow" => (A_DENY, true),
+        _ => return Err(format!("Invalid action {}", name)),
     };
-    let raw_key = rule[1].to_string().replace("!", "");
-    let mode = if raw_key.starts_with("*") {
-        M_END
-    } else if raw_key.ends_with("*") {
-        M_START
-    } else {
-        M_EQUAL
+    let pattern = match rule.get(1) {
+        Some(pattern) => *pattern,
+        None => return Err(format!("Missing domain pattern after {}", name)),
     };
-    let reverse = rule[1].starts_with("!");
-    let key = raw_key.replace("*", "");
-    let value = if rule.len() > 3 {
-        Some(rule[3].to_string())
+    let reverse = allow || pattern.starts_with("!");
+    let (mode, key) = parse_pattern(pattern.trim_start_matches('!'))?;
+
+    // Values are either `<value>`, which answers with an A record, or
+    // `<type> <value...>` for any other record type.
+    let (qtype, value) = if rule.len() > 3 {
+        let qtype = match QueryType::from_name(rule[2]) {
+            Some(qtype) => qtype,
+            None => return Err(format!("Invalid record type {}", rule[2])),
+        };
+        (Some(qtype), Some(words_after(raw, 3).to_string()))
+    } else if rule.len() > 2 {
+        (None, Some(rule[2].to_string()))
     } else {
-        None
+        (None, None)
     };
 
-    Rule {
+    // Catch values that can't be answered with now, rather than on every
+    // query for the name.
+    if action == A_APPEND {
+        let value = value.as_deref().unwrap_or("127.0.0.1");
+        let qtype = qtype.unwrap_or(QueryType::A);
+        if let Err(err) = DnsRecord::from_text("", qtype, QueryClass::IN, 0, value) {
+            return Err(format!("Invalid value {}: {}", value, err));
+        }
+    }
+
+    Ok(Rule {
         action,
         mode,
         reverse,
         key,
+        qtype,
         value,
+    })
+}
+
+/// What is left of `line` after its first `count` words, with the spacing
+/// within it kept as it was.
+fn words_after(line: &str, count: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..count {
+        rest = rest
+            .trim_start_matches(|c: char| !c.is_whitespace())
+            .trim_start();
+    }
+    rest.trim_end()
+}
+
+/// Split a domain pattern into its match mode and the name it is about.
+/// Names are compared without case or a trailing dot. Patterns between
+/// slashes are regular expressions, and patterns with wildcards inside a
+/// label are globs, both turned into the regex kept as the key.
+pub fn parse_pattern(pattern: &str) -> std::result::Result<(u8, String), String> {
+    if let Some(regex) = pattern
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_suffix('/'))
+    {
+        return Ok((M_REGEX, checked_regex(pattern, regex.to_string())?));
+    }
+
+    let name = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let (mode, key) = if name == "*" || name == "**" {
+        (M_ANY, "")
+    } else if let Some(parent) = name.strip_prefix("**.") {
+        (M_TREE, parent)
+    } else if let Some(parent) = name.strip_prefix("*.") {
+        (M_END, parent)
+    } else if let Some(start) = name.strip_suffix(".*") {
+        (M_START, start)
+    } else {
+        (M_EQUAL, name.as_str())
+    };
+
+    if mode != M_ANY && key.contains(['*', '?', '[']) {
+        return Ok((M_GLOB, checked_regex(pattern, glob_regex(&name)?)?));
+    }
+    if mode != M_ANY && key.split('.').any(str::is_empty) {
+        return Err(format!("Invalid domain pattern {}", pattern));
+    }
+
+    Ok((mode, key.to_string()))
+}
+
+fn checked_regex(pattern: &str, regex: String) -> std::result::Result<String, String> {
+    match RegexBuilder::new(&regex).case_insensitive(true).build() {
+        Ok(_) => Ok(regex),
+        Err(err) => Err(format!("Invalid pattern {}: {}", pattern, err)),
+    }
+}
+
+/// The anchored regex for a glob. A leading `*.` or `**.` and a trailing
+/// `.*` mean the same labels as in the plain patterns, a `*` label in
+/// between is one label, and within a label `*` is any run of characters,
+/// `?` one character and `[a-z]` or `[!a-z]` one of a set.
+fn glob_regex(glob: &str) -> std::result::Result<String, String> {
+    let labels: Vec<&str> = glob.split('.').collect();
+    let last = labels.len() - 1;
+    let mut regex = String::from("^");
+
+    for (i, label) in labels.iter().enumerate() {
+        match (i, *label) {
+            (0, "**") if last > 0 => {
+                regex.push_str(r"(?:[^.]+\.)*");
+                continue;
+            }
+            (0, "*") if last > 0 => {
+                regex.push_str(r"(?:[^.]+\.)+");
+                continue;
+            }
+            // The leading wildcard brings the dot before this label.
+            (1, _) if labels[0] == "*" || labels[0] == "**" => {}
+            (i, "*") if i == last && i > 0 => {
+                regex.push_str(r"(?:\.[^.]+)+");
+                continue;
+            }
+            (i, _) if i > 0 => regex.push_str(r"\."),
+            _ => {}
+        }
+        if label.is_empty() {
+            return Err(format!("Invalid domain pattern {}", glob));
+        }
+        if *label == "*" || *label == "**" {
+            regex.push_str("[^.]+");
+            continue;
+        }
+
+        let mut chars = label.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => regex.push_str("[^.]*"),
+                '?' => regex.push_str("[^.]"),
+                '[' => {
+                    let mut class = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            closed = true;
+                            break;
+                        }
+                        class.push(c);
+                    }
+                    if !closed || class.is_empty() {
+                        return Err(format!("Invalid pattern {}: unclosed or empty [", glob));
+                    }
+                    regex.push('[');
+                    let class = match class.strip_prefix('!') {
+                        Some(rest) => {
+                            regex.push('^');
+                            rest
+                        }
+                        None => &class,
+                    };
+                    for c in class.chars() {
+                        if c == '\\' || c == '[' {
+                            regex.push('\\');
+                        }
+                        regex.push(c);
+                    }
+                    regex.push(']');
+                }
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
     }
+
+    regex.push('$');
+    Ok(regex)
 }
 
 fn ignore_line(line: &str) -> bool {
-    line.starts_with("#") || line.trim().is_empty()
+    let line = line.trim();
+    line.starts_with("#") || line.is_empty()
 }
 
-pub fn parse_rules(file_path: PathBuf) -> Vec<Rule> {
-    let rules = std::fs::read_to_string(file_path).unwrap();
+/// Parse the rules in a file. Invalid lines fail the whole file, or with
+/// `OnError::Skip` are left out and added to `skipped`.
+pub fn parse_rules(
+    file_path: PathBuf,
+    on_error: OnError,
+    skipped: &mut Vec<RuleParseError>,
+) -> Result<Vec<Rule>> {
+    let rules = match std::fs::read_to_string(&file_path) {
+        Ok(rules) => rules,
+        Err(err) => {
+            return Err(format!("Reading rules from {:?} failed: {}", file_path, err).into())
+        }
+    };
     let rules: Vec<&str> = rules.split("\n").collect();
     let mut parsed_rules: Vec<Rule> = Vec::new();
 
-    for line in rules {
+    for (i, line) in rules.into_iter().enumerate() {
         if ignore_line(line) {
             continue;
         }
 
-        let rule = parse_rule(line);
-        parsed_rules.push(rule);
+        match parse_rule(line) {
+            Ok(rule) => parsed_rules.push(rule),
+            Err(reason) => {
+                let err = RuleParseError {
+                    file: file_path.clone(),
+                    line: i + 1,
+                    reason,
+                };
+                if on_error == OnError::Fail {
+                    return Err(err.into());
+                }
+                log::warn!("Skipping invalid rule at {}", err);
+                skipped.push(err);
+            }
+        }
     }
 
-    parsed_rules
+    Ok(parsed_rules)
 }
 
-pub fn parse_rules_dir(dir_path: PathBuf) -> Vec<Rule> {
+pub fn parse_rules_dir(
+    dir_path: PathBuf,
+    on_error: OnError,
+    skipped: &mut Vec<RuleParseError>,
+) -> Result<Vec<Rule>> {
     let mut parsed_rules: Vec<Rule> = Vec::new();
 
-    for entry in std::fs::read_dir(dir_path).unwrap() {
-        let entry = entry.unwrap();
+    // Files are read in name order, so which of two equal rules applies
+    // doesn't depend on the file system.
+    let mut entries = match std::fs::read_dir(&dir_path) {
+        Ok(entries) => entries.collect::<std::io::Result<Vec<_>>>()?,
+        Err(err) => return Err(format!("Reading rules from {:?} failed: {}", dir_path, err).into()),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
         let path = entry.path();
 
         if path.is_dir() {
-            let mut dir_rules = parse_rules_dir(path);
+            let mut dir_rules = parse_rules_dir(path, on_error, skipped)?;
             parsed_rules.append(&mut dir_rules);
-        } else if entry.file_name().to_str().unwrap().ends_with(".rules") {
-            let mut file_rules = parse_rules(path);
+        } else if entry.file_name().to_string_lossy().ends_with(".rules") {
+            let mut file_rules = parse_rules(path, on_error, skipped)?;
             parsed_rules.append(&mut file_rules);
         }
     }
 
-    parsed_rules
+    Ok(parsed_rules)
 }
 
-pub fn parse_rules_config(config: &Vec<RulesSettings>) -> Vec<Rule> {
+pub fn parse_rules_config(config: &Vec<RulesSettings>) -> Result<Vec<Rule>> {
     let mut parsed_rules: Vec<Rule> = Vec::new();
+    let mut skipped: Vec<RuleParseError> = Vec::new();
 
     for rule_file in config {
         let path = utils::get_path(&rule_file.path);
 
         if rule_file.load_as == "file" {
-            let mut file_rules = parse_rules(path);
+            let mut file_rules = parse_rules(path, rule_file.on_error, &mut skipped)?;
             parsed_rules.append(&mut file_rules);
         } else if rule_file.load_as == "dir" {
-            let mut dir_rules = parse_rules_dir(path);
+            let mut dir_rules = parse_rules_dir(path, rule_file.on_error, &mut skipped)?;
             parsed_rules.append(&mut dir_rules);
+        } else {
+            return Err(format!("Invalid load_as {} for rules", rule_file.load_as).into());
         }
     }
 
-    parsed_rules
+    if !skipped.is_empty() {
+        let mut files: Vec<_> = skipped
+            .iter()
+            .map(|err| err.file.display().to_string())
+            .collect();
+        files.dedup();
+        log::warn!(
+            "Skipped {} invalid rule lines in {}",
+            skipped.len(),
+            files.join(", ")
+        );
+    }
+
+    Ok(parsed_rules)
 }
 
-pub fn match_rule(rules: &Vec<Rule>, query: &str) -> Option<Rule> {
-    let mut matched_rule: Option<Rule> = None;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for rule in rules {
-        let mut matched = false;
+    #[test]
+    fn patterns_are_label_aware() {
+        let pattern = |text: &str| parse_pattern(text).unwrap();
 
-        if rule.mode == M_EQUAL {
-            matched = query == rule.key;
-        } else if rule.mode == M_END {
-            matched = query.ends_with(&rule.key);
-        } else if rule.mode == M_START {
-            matched = query.starts_with(&rule.key);
-        }
+        assert_eq!(pattern("domain.com"), (M_EQUAL, "domain.com".to_string()));
+        assert_eq!(pattern("Domain.COM."), (M_EQUAL, "domain.com".to_string()));
+        assert_eq!(pattern("*.domain.com"), (M_END, "domain.com".to_string()));
+        assert_eq!(pattern("**.domain.com"), (M_TREE, "domain.com".to_string()));
+        assert_eq!(pattern("domain.com.*"), (M_START, "domain.com".to_string()));
+        assert_eq!(pattern("*"), (M_ANY, "".to_string()));
+        assert_eq!(pattern("**"), (M_ANY, "".to_string()));
+    }
+
+    #[test]
+    fn globs_and_regexes_become_regexes() {
+        let matches = |pattern: &str, name: &str| {
+            let (mode, key) = parse_pattern(pattern).unwrap();
+            assert!(mode == M_GLOB || mode == M_REGEX, "{}", pattern);
+            regex::Regex::new(&key).unwrap().is_match(name)
+        };
+
+        assert!(matches("tracker-*.cdn.*", "tracker-12.cdn.example.com"));
+        assert!(!matches("tracker-*.cdn.*", "tracker-12.cdn"));
+        assert!(!matches("tracker-*.cdn.*", "a.tracker-12.cdn.com"));
+        assert!(matches("*.ads-?.com", "x.y.ads-1.com"));
+        assert!(!matches("*.ads-?.com", "ads-1.com"));
+        assert!(matches("**.ads[0-9].com", "ads7.com"));
+        assert!(!matches("ads[!0-9].com", "ads7.com"));
+        assert!(matches("*domain.com", "baddomain.com"));
+        assert!(!matches("*domain.com", "a.baddomain.com"));
+        assert!(matches("ads.*.com", "ads.x.com"));
+        assert!(matches("*.*", "x.com"));
+        assert!(!matches("*.*", "com"));
+        assert!(!matches("ads.*.com", "ads.x.y.com"));
+        assert!(matches("/^ads[0-9]+\\./", "ads12.example.com"));
+        assert!(!matches("/^ads[0-9]+\\./", "myads12.example.com"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let reason = |text: &str| parse_pattern(text).unwrap_err();
+
+        assert!(reason("/ads(/").starts_with("Invalid pattern /ads(/"));
+        assert!(reason("ads[.com").starts_with("Invalid pattern ads[.com"));
+        assert_eq!(reason("domain..com"), "Invalid domain pattern domain..com");
+    }
+
+    #[test]
+    fn rules_keep_their_value() {
+        let rule = parse_rule("apnd *.Redirect.com. MX 10 mx.redirect.com\r").unwrap();
 
-        if matched {
-            matched_rule = Some(rule.clone());
-            break;
+        assert_eq!(rule.action, A_APPEND);
+        assert_eq!(rule.mode, M_END);
+        assert_eq!(rule.key, "redirect.com");
+        assert_eq!(rule.qtype, Some(QueryType::MX));
+        assert_eq!(rule.value.as_deref(), Some("10 mx.redirect.com"));
+    }
+
+    #[test]
+    fn allow_is_a_deny_exception() {
+        let allow = parse_rule("allow business.facebook.com").unwrap();
+        let negated = parse_rule("deny !business.facebook.com").unwrap();
+
+        for rule in [allow, negated] {
+            assert_eq!(rule.action, A_DENY);
+            assert!(rule.reverse);
+            assert_eq!(rule.key, "business.facebook.com");
         }
+        assert!(!parse_rule("deny business.facebook.com").unwrap().reverse);
     }
 
-    matched_rule
+    #[test]
+    fn invalid_lines_fail_or_are_skipped() {
+        let path = std::env::temp_dir().join(format!("mindns-rules-{}.rules", std::process::id()));
+        std::fs::write(
+            &path,
+            "# comment\ndeny a.com\ndny b.com\napnd\napnd c.com MX\napnd d.com TYP 1\n  \
+             # indented comment\napnd e.com 10.0.0.300\napnd\tf.com  TXT \"a  b\"\n",
+        )
+        .unwrap();
+
+        let mut skipped = Vec::new();
+        let failed = parse_rules(path.clone(), OnError::Fail, &mut skipped);
+        let rules = parse_rules(path.clone(), OnError::Skip, &mut skipped);
+        std::fs::remove_file(&path).unwrap();
+
+        let err = failed.unwrap_err().to_string();
+        assert_eq!(err, format!("{}:3: Invalid action dny", path.display()));
+        let rules = rules.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1].value.as_deref(), Some("\"a  b\""));
+        let lines: Vec<_> = skipped.iter().map(|err| err.line).collect();
+        assert_eq!(lines, [3, 4, 5, 6, 8]);
+        assert_eq!(skipped[1].reason, "Missing domain pattern after apnd");
+        assert_eq!(skipped[3].reason, "Invalid record type TYP");
+        for (err, value) in [(&skipped[2], "MX"), (&skipped[4], "10.0.0.300")] {
+            let prefix = format!("{}:{}: Invalid value {}: ", path.display(), err.line, value);
+            assert!(err.to_string().starts_with(&prefix), "{}", err);
+        }
+    }
 }